@@ -0,0 +1,454 @@
+//! Whole pipeline of a run: input files parsed on a thread pool, aggregates merged, saved and exported.
+//! CSV rows and database and parquet records are buffered per input file, and written once it is fully parsed.
+
+use config::Config;
+use csv_export::{self, CsvExport};
+use distribution::Backend;
+use error::{ErrorKind, ParseError};
+use export;
+use glob::glob;
+use json_export::JsonLayout;
+use manifest::{self, FileEntry, FileStatus, Manifest};
+use num_cpus;
+use parquet_export::ParquetExport;
+use parse::{self, Parsed, StatsLog};
+use process;
+use quality::QualityReport;
+use quarantine;
+use segment;
+use sqlite_export::Database;
+use state::State;
+use std::{
+    fs,
+    path::Path,
+    sync::{mpsc, Arc},
+    sync::mpsc::{Receiver, SyncSender},
+};
+use threadpool::ThreadPool;
+use trend;
+use usage;
+
+/// Name of the aggregates snapshot in the output directory
+pub const SNAPSHOT_FILENAME: &str = "snapshot.json.xz";
+
+/// Settings of a run, as given on the command line
+pub struct Options {
+    /// Directory searched for `.xz` input files
+    pub source: String,
+    /// Output directory
+    pub target: String,
+    /// Directory where invalid input files are moved
+    pub quarantine: String,
+    /// Delete invalid input files instead of moving them to the quarantine
+    pub delete_invalid: bool,
+    /// Only parse new files, and add them to the aggregates of the previous run
+    pub incremental: bool,
+    /// Width of the buckets of the dps histograms
+    pub dps_steps: u32,
+    /// The dps histograms are written up to this dps
+    pub dps_max: u32,
+    /// Storage of the dps and clear times
+    pub backend: Backend,
+    /// JSON export of the statistics, none when None
+    pub json_layout: Option<JsonLayout>,
+    /// CSV file of the accepted members
+    pub csv: Option<String>,
+    /// SQLite database of the accepted encounters and statistics
+    pub sqlite: Option<String>,
+    /// Directory of the parquet datasets of the accepted encounters
+    pub parquet: Option<String>,
+}
+
+// Aggregates of one file, sent to the main thread to be merged
+struct Message {
+    key: String,
+    result: Result<(State, FileEntry), ParseError>,
+}
+
+/// Parse every input file of the source directory, aggregate the accepted encounters and write every output.
+/// `fingerprint` identifies the settings the aggregates are built with, see `state::fingerprint`.
+/// Invalid input files are reported and quarantined, only the setup of the outputs can fail.
+pub fn analyse(config: Config, options: &Options, fingerprint: &str) -> Result<(), String> {
+    let snapshot_filename = format!("{}/{}", options.target, SNAPSHOT_FILENAME);
+    let manifest_filename = format!("{}/manifest.json", options.target);
+    let (mut manifest, mut state) = if options.incremental {
+        load_previous_run(&manifest_filename, &snapshot_filename, fingerprint)
+    } else {
+        (Manifest::new(), State::new())
+    };
+
+    let files = input_files(&options.source, &options.quarantine);
+    let mut to_parse = Vec::new();
+    for string in &files {
+        let key = manifest::file_key(string, &options.source);
+        match manifest.status(&key, string) {
+            Ok(FileStatus::Unchanged) => {}
+            Ok(FileStatus::Touched(entry)) => {
+                manifest.files.insert(key, entry);
+            }
+            Ok(FileStatus::New) | Err(_) => to_parse.push(string.clone()),
+            Ok(FileStatus::Changed) => {
+                // The previous content of the file cannot be removed from the aggregates
+                println!("{} changed since the previous run, rebuilding from scratch", string);
+                manifest = Manifest::new();
+                state = State::new();
+                to_parse = files.clone();
+                break;
+            }
+        }
+    }
+    println!("{} files to parse, {} files in total", to_parse.len(), files.len());
+    // Rows of the files aggregated by a previous run are kept
+    let append = !manifest.files.is_empty();
+    let database = match options.sqlite {
+        Some(ref filename) => Some(Arc::new(Database::open(filename, append)?)),
+        None => None,
+    };
+    let parquet_export = match options.parquet {
+        Some(ref directory) => Some(Arc::new(ParquetExport::new(directory, append)?)),
+        None => None,
+    };
+    let csv_export = match options.csv {
+        Some(ref filename) => Some(Arc::new(CsvExport::new(filename, append, &config.csv.buffs)?)),
+        None => None,
+    };
+
+    let config = Arc::new(config);
+    let full_cpus = num_cpus::get();
+    let mut usable_cpus = full_cpus.saturating_sub(3);
+    println!("Number of virtual core: {}", full_cpus);
+    if usable_cpus <= 1 {
+        usable_cpus = 1;
+    }
+    let (tx, rx): (SyncSender<Message>, Receiver<Message>) = mpsc::sync_channel(usable_cpus);
+    let thread_pool_decompress: ThreadPool = ThreadPool::new(usable_cpus);
+    for string in to_parse {
+        let thread_tx = tx.clone();
+        let config = config.clone();
+        let source = options.source.clone();
+        let quarantine_dir = options.quarantine.clone();
+        let delete_invalid = options.delete_invalid;
+        let dps_steps = options.dps_steps;
+        let backend = options.backend;
+        let csv_export = csv_export.clone();
+        let database = database.clone();
+        let parquet_export = parquet_export.clone();
+        thread_pool_decompress.execute(move || {
+            // Each file is aggregated on its own, so that an invalid file contributes nothing
+            let mut file_state = State::new();
+            let mut csv_rows = Vec::new();
+            let mut encounters = Vec::new();
+            let result = parse::read_file(&string, &config.rules, |one_fight| match one_fight {
+                Parsed::Accepted(one_fight) => {
+                    if csv_export.is_some() {
+                        csv_export::rows(&one_fight, &config, &mut csv_rows);
+                    }
+                    if database.is_some() || parquet_export.is_some() {
+                        encounters.extend(process::record(&one_fight, &config.classes, &config.regions));
+                    }
+                    store(one_fight, &mut file_state, &config, dps_steps, backend)
+                }
+                Parsed::Rejected(log) => {
+                    process::store_rejected(log, &mut file_state.quality, &config.regions)
+                }
+            })
+            .and_then(|_| {
+                manifest::file_entry(&string).map_err(|e| ParseError::new(&string, ErrorKind::Io(e)))
+            });
+            if let (Ok(_), Some(csv_export)) = (&result, &csv_export) {
+                if let Err(e) = csv_export.write(&csv_rows) {
+                    println!("Unable to write CSV rows of {}: {}", string, e);
+                }
+            }
+            if let (Ok(_), Some(database)) = (&result, &database) {
+                if let Err(e) = database.insert(&manifest::file_key(&string, &source), &encounters) {
+                    println!("Unable to insert the encounters of {}: {}", string, e);
+                }
+            }
+            if let (Ok(_), Some(parquet_export)) = (&result, &parquet_export) {
+                parquet_export.write(&manifest::file_key(&string, &source), &encounters);
+            }
+            if let Err(ref err) = result {
+                println!("{}", err);
+                // An unreadable file may be fine, only an invalid content is removed
+                if err.is_invalid_content() {
+                    if delete_invalid {
+                        if let Err(e) = fs::remove_file(&string) {
+                            println!("Unable to delete {}: {}", string, e);
+                        }
+                    } else if let Err(e) =
+                        quarantine::quarantine(&string, &source, &quarantine_dir, err)
+                    {
+                        println!("{}", e);
+                    }
+                }
+            }
+            let message = Message {
+                key: manifest::file_key(&string, &source),
+                result: result.map(|entry| (file_state, entry)),
+            };
+            thread_tx.send(message).unwrap();
+        });
+    }
+
+    drop(tx);
+    // Failures only describe this run, they are not persisted with the aggregates
+    let mut failures = QualityReport::new();
+    for received in rx {
+        match received.result {
+            Ok((mut file_state, entry)) => {
+                file_state.quality.add_file();
+                state.merge(file_state);
+                manifest.files.insert(received.key, entry);
+            }
+            Err(err) => failures.add_failed_file(err.reason(), err.to_string()),
+        }
+    }
+    // Workers may still hold their clones of the exports after sending their last message
+    thread_pool_decompress.join();
+    // Reported once the aggregates are saved, a parquet file without its footer is unreadable
+    let parquet_result = match parquet_export {
+        Some(parquet_export) => Arc::try_unwrap(parquet_export)
+            .map_err(|_| "Parquet files still in use, they were not closed".to_string())
+            .and_then(ParquetExport::close),
+        None => Ok(()),
+    };
+    let _ = fs::create_dir_all(&options.target);
+    // Without its parquet rows, the run is not recorded, so that the next one parses its files again
+    if parquet_result.is_ok() {
+        if let Err(e) = state
+            .save(&snapshot_filename, fingerprint)
+            .and_then(|_| manifest.save(&manifest_filename))
+        {
+            println!("{}", e);
+        }
+    }
+    state.quality.merge(failures);
+    export::write_outputs(
+        &options.target,
+        options.dps_max,
+        options.dps_steps,
+        &config,
+        options.json_layout.as_ref(),
+        database.as_deref(),
+        state,
+    );
+    parquet_result
+}
+
+// Add an accepted fight to every aggregate
+fn store(one_fight: StatsLog, state: &mut State, config: &Config, dps_steps: u32, backend: Backend) {
+    usage::store(&one_fight, &mut state.data.usage, &config.usage);
+    trend::store(&one_fight, &mut state.data.trends, config, backend);
+    segment::store(&one_fight, &mut state.data.segments, config, backend);
+    process::store(
+        one_fight,
+        dps_steps,
+        backend,
+        &mut state.data,
+        config,
+        &mut state.quality,
+    )
+}
+
+/// Parse and filter every input file of `source`, like `analyse` does, but write nothing.
+/// Invalid files are reported and left in place, the report tells what would be accepted and rejected.
+pub fn validate(config: Config, source: &str, quarantine: &str) -> QualityReport {
+    let files = input_files(source, quarantine);
+    println!("{} files to validate", files.len());
+    let config = Arc::new(config);
+    let usable_cpus = num_cpus::get().saturating_sub(3).max(1);
+    let (tx, rx) = mpsc::sync_channel(usable_cpus);
+    let thread_pool_decompress: ThreadPool = ThreadPool::new(usable_cpus);
+    for string in files {
+        let thread_tx = tx.clone();
+        let config = config.clone();
+        thread_pool_decompress.execute(move || {
+            // The aggregates are only built for the member counts of the report
+            let mut file_state = State::new();
+            let result = parse::read_file(&string, &config.rules, |one_fight| match one_fight {
+                Parsed::Accepted(one_fight) => process::store(
+                    one_fight,
+                    1,
+                    Backend::Exact,
+                    &mut file_state.data,
+                    &config,
+                    &mut file_state.quality,
+                ),
+                Parsed::Rejected(log) => {
+                    process::store_rejected(log, &mut file_state.quality, &config.regions)
+                }
+            });
+            thread_tx.send(result.map(|_| file_state.quality)).unwrap();
+        });
+    }
+    drop(tx);
+    let mut quality = QualityReport::new();
+    for received in rx {
+        match received {
+            Ok(mut file_quality) => {
+                file_quality.add_file();
+                quality.merge(file_quality);
+            }
+            Err(err) => {
+                println!("{}", err);
+                quality.add_failed_file(err.reason(), err.to_string());
+            }
+        }
+    }
+    quality
+}
+
+/// Merge the snapshots of several runs, save the result in the output directory and write every output.
+/// Only the statistics are written to the SQLite database, encounters are not part of the snapshots.
+pub fn merge(
+    config: &Config,
+    options: &Options,
+    snapshots: &[String],
+    fingerprint: &str,
+) -> Result<(), String> {
+    let mut state = State::new();
+    for snapshot in snapshots {
+        state.merge(State::load(snapshot, fingerprint)?);
+    }
+    let _ = fs::create_dir_all(&options.target);
+    let snapshot_filename = format!("{}/{}", options.target, SNAPSHOT_FILENAME);
+    if let Err(e) = state.save(&snapshot_filename, fingerprint) {
+        println!("{}", e);
+    }
+    let database = match options.sqlite {
+        Some(ref filename) => Some(Database::open(filename, false)?),
+        None => None,
+    };
+    export::write_outputs(
+        &options.target,
+        options.dps_max,
+        options.dps_steps,
+        config,
+        options.json_layout.as_ref(),
+        database.as_ref(),
+        state,
+    );
+    Ok(())
+}
+
+// Input files of the source directory, except the quarantined ones
+fn input_files(source: &str, quarantine: &str) -> Vec<String> {
+    let search = format!("{}/**/*.xz", source);
+    let mut files = Vec::new();
+    for entry in glob(&search).expect("Failed to read glob pattern") {
+        let os_string = entry.unwrap().into_os_string();
+        let string = os_string.into_string().unwrap();
+        if Path::new(&string).starts_with(quarantine) {
+            continue;
+        }
+        files.push(string);
+    }
+    files
+}
+
+// Manifest and aggregates of the previous run, or empty ones if they cannot be reused
+fn load_previous_run(
+    manifest_filename: &str,
+    snapshot_filename: &str,
+    fingerprint: &str,
+) -> (Manifest, State) {
+    let previous = Manifest::load(manifest_filename)
+        .and_then(|manifest| Ok((manifest, State::load(snapshot_filename, fingerprint)?)));
+    match previous {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{}, rebuilding from scratch", e);
+            (Manifest::new(), State::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::store;
+    use config::Config;
+    use distribution::Backend;
+    use serde_json;
+    use state::{self, State};
+    use std::{env, fs, process};
+
+    // Shipped configuration, with the segments, the trends and the skills enabled
+    fn config() -> Config {
+        let shipped = fs::read_to_string("config.toml").unwrap();
+        let filename = env::temp_dir().join(format!("merge-{}.toml", process::id()));
+        fs::write(
+            &filename,
+            shipped
+                .replace("#[[segments]]\n#buff", "[[segments]]\nbuff")
+                .replace("#[trend]\n#bucket", "[trend]\nbucket")
+                .replace("#[skills]\n#enabled", "[skills]\nenabled"),
+        )
+        .unwrap();
+        let config = Config::load(filename.to_str().unwrap());
+        fs::remove_file(&filename).unwrap();
+        config.unwrap()
+    }
+
+    fn fight(region: &str, timestamp: u64, boss_id: u32, party: &[(&str, u32, &str)]) -> String {
+        let members: Vec<String> = party
+            .iter()
+            .map(|&(class, dps, uptime)| {
+                format!(
+                    r#"{{"buffUptime": [{{"Key": "8888889", "Value": "{}"}}], "playerClass": "{}", "playerDps": "{}",
+                    "playerServer": "Killian", "playerName": "{}{}", "skillLog": [{{"skillId": "100", "skillHits": "45",
+                    "skillTotalDamage": "{}", "skillDamagePercent": "17", "skillCritRate": "92", "skillHighestCrit": "841775",
+                    "skillLowestCrit": "244"}}]}}"#,
+                    uptime, class, dps, class, dps, dps * 300 / 2
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"content": {{"areaId": 444, "bossId": {}, "fightDuration": "{}", "timestamp": {}, "members": [{}]}},
+            "directory": "{}.1"}}"#,
+            boss_id,
+            240 + timestamp % 97,
+            timestamp,
+            members.join(","),
+            region
+        )
+    }
+
+    #[test]
+    fn merged_snapshots_are_one_run_over_both_inputs() {
+        let config = config();
+        let fights = vec![
+            fight("EU", 1_548_300_000, 1000, &[("Warrior", 3_684_443, "63"), ("Priest", 120_000, "0")]),
+            fight("EU", 1_549_300_000, 1000, &[("Warrior", 2_000_000, "100"), ("Lancer", 900_000, "12")]),
+            fight("NA", 1_540_000_000, 2000, &[("Warrior", 1_500_000, "7"), ("Gunslinger", 1, "1")]),
+            fight("EU", 1_548_900_000, 2000, &[("Priest", 150_000, "99"), ("Mystic", 130_000, "55")]),
+            fight("NA", 1_549_000_000, 1000, &[("Lancer", 800_000, "0"), ("Warrior", 4_100_000, "100")]),
+        ];
+        let states = |fights: &[String]| {
+            let mut state = State::new();
+            for fight in fights {
+                let fight = serde_json::from_str(fight).unwrap();
+                store(fight, &mut state, &config, 100_000, Backend::Exact);
+            }
+            state
+        };
+        let fingerprint = state::fingerprint(&config, 100_000);
+        let directory = env::temp_dir().join(format!("merge-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let mut merged = State::new();
+        for (name, shard) in &[("first", &fights[..2]), ("second", &fights[2..])] {
+            let filename = directory.join(name);
+            let filename = filename.to_str().unwrap();
+            states(shard).save(filename, &fingerprint).unwrap();
+            merged.merge(State::load(filename, &fingerprint).unwrap());
+        }
+        fs::remove_dir_all(&directory).unwrap();
+        let single = states(&fights);
+        assert!(!single.data.fights.is_empty() && !single.data.segments.is_empty() && !single.data.trends.is_empty());
+        // Json maps are sorted, the order of the hash maps does not matter
+        assert_eq!(
+            serde_json::to_value(&merged).unwrap(),
+            serde_json::to_value(&single).unwrap()
+        );
+    }
+}
@@ -0,0 +1,526 @@
+//! Configuration file: patch windows, classes, rejection rules and the settings of the outputs.
+
+extern crate chrono_tz;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
+use self::chrono_tz::Tz;
+use self::serde::{Serialize, Serializer};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Offset, TimeZone};
+use distribution::QuantileMethod;
+use rules::Rule;
+use segment::Segment;
+use trend::TrendBucket;
+use std::{collections::HashMap, fs::File, io::prelude::*, path::Path};
+
+/// region -> patch name -> [start, end) as unix timestamps
+pub type RegionMap = HashMap<String, HashMap<String, (u64, u64)>>;
+
+/// Patch windows, classes, rejection rules and export settings
+pub struct Config {
+    /// Patch windows of every region
+    pub regions: RegionMap,
+    /// Canonical classes, their role and aliases
+    pub classes: ClassRegistry,
+    /// A fight matching any of the rules is rejected
+    pub rules: Vec<Rule>,
+    /// Quantiles and descriptive statistics exported
+    pub statistics: Statistics,
+    /// Columns of the CSV export
+    pub csv: CsvConfig,
+    /// Names of the buffs whose uptime is exported
+    pub buffs: BuffConfig,
+    /// Class dps split on the uptime of a buff
+    pub segments: Vec<Segment>,
+    /// Patch comparison reports
+    pub report: ReportConfig,
+    /// Timezones of the usage time series
+    pub usage: UsageConfig,
+    /// Buckets of the trend series, no trend series when None
+    pub trend: Option<TrendBucket>,
+    /// Skill damage statistics
+    pub skills: SkillConfig,
+}
+
+/// Timezones of the usage time series and of the calendar trend buckets
+#[derive(Serialize)]
+pub struct UsageConfig {
+    timezone: Timezone,
+    regions: HashMap<String, Timezone>,
+}
+
+impl UsageConfig {
+    /// Timezone of the region, or the default one
+    pub fn timezone(&self, region: &str) -> Timezone {
+        self.regions.get(region).cloned().unwrap_or(self.timezone)
+    }
+}
+
+/// A fixed offset from UTC, or a zone of the tz database whose offset follows daylight saving time
+#[derive(Clone, Copy)]
+pub enum Timezone {
+    /// Same offset all year long
+    Fixed(FixedOffset),
+    /// Name of the tz database, such as Europe/Paris
+    Named(Tz),
+}
+
+impl Timezone {
+    /// Offset from UTC at a unix timestamp
+    pub fn offset(self, timestamp: i64) -> FixedOffset {
+        match self {
+            Timezone::Fixed(offset) => offset,
+            Timezone::Named(zone) => DateTime::from_timestamp(timestamp, 0).map_or_else(
+                || FixedOffset::east_opt(0).unwrap(),
+                |date| zone.offset_from_utc_datetime(&date.naive_utc()).fix(),
+            ),
+        }
+    }
+}
+
+// As written in the configuration
+impl Serialize for Timezone {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Timezone::Fixed(offset) => serializer.serialize_str(&offset.to_string()),
+            Timezone::Named(zone) => serializer.serialize_str(zone.name()),
+        }
+    }
+}
+
+/// `[csv]` section
+#[derive(Deserialize, Default)]
+pub struct CsvConfig {
+    /// Buff ids whose uptime gets a column in the CSV export
+    #[serde(default)]
+    pub buffs: Vec<String>,
+}
+
+/// `[buffs]` section
+#[derive(Deserialize, Default)]
+pub struct BuffConfig {
+    /// Buff id -> name, the uptime histograms are only written for these buffs
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+}
+
+/// `[skills]` section
+#[derive(Deserialize, Default)]
+pub struct SkillConfig {
+    /// Aggregate the skill logs, off by default as they take more memory than the dps
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// Settings of the patch comparison reports
+#[derive(Deserialize)]
+pub struct ReportConfig {
+    /// Relative change above which a value is flagged, 0.1 for 10%
+    #[serde(default = "default_threshold")]
+    pub threshold: f64,
+}
+
+impl Default for ReportConfig {
+    fn default() -> ReportConfig {
+        ReportConfig {
+            threshold: default_threshold(),
+        }
+    }
+}
+
+fn default_threshold() -> f64 {
+    0.1
+}
+
+/// Quantiles exported for class dps and clear times
+#[derive(Deserialize)]
+pub struct Statistics {
+    /// Quantiles between 0 and 1, exported in `dps_quantiles/` and `clear_time_quantiles/`
+    #[serde(default = "default_quantiles")]
+    pub quantiles: Vec<f64>,
+    /// How the quantiles and the medians are picked among the values
+    #[serde(default = "default_method")]
+    pub method: QuantileMethod,
+    /// Fraction of the lowest and of the highest values ignored by the trimmed mean
+    #[serde(default = "default_trim")]
+    pub trim: f64,
+}
+
+impl Default for Statistics {
+    fn default() -> Statistics {
+        Statistics {
+            quantiles: default_quantiles(),
+            method: default_method(),
+            trim: default_trim(),
+        }
+    }
+}
+
+fn default_quantiles() -> Vec<f64> {
+    vec![0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 0.99]
+}
+
+fn default_method() -> QuantileMethod {
+    QuantileMethod::NearestRank
+}
+
+fn default_trim() -> f64 {
+    0.1
+}
+
+/// Role of a class in a party
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// `tank` in the configuration
+    Tank,
+    /// `healer` in the configuration
+    Healer,
+    /// `dps` in the configuration, any damage dealer
+    Dps,
+}
+
+impl Role {
+    /// Name used in the outputs and in the configuration
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Role::Tank => "tank",
+            Role::Healer => "healer",
+            Role::Dps => "dps",
+        }
+    }
+}
+
+/// Maps the raw `playerClass` strings sent by the meters to a canonical class
+#[derive(Serialize)]
+pub struct ClassRegistry {
+    roles: HashMap<String, Role>,
+    aliases: HashMap<String, String>,
+}
+
+impl ClassRegistry {
+    /// Canonical class of a raw `playerClass`, None for an unknown class
+    pub fn get(&self, raw_class: &str) -> Option<&String> {
+        self.aliases.get(raw_class)
+    }
+
+    /// Role of a canonical class
+    pub fn role(&self, class: &str) -> Option<Role> {
+        self.roles.get(class).cloned()
+    }
+}
+
+#[derive(Deserialize)]
+struct RawConfig {
+    regions: HashMap<String, HashMap<String, RawWindow>>,
+    classes: HashMap<String, RawClass>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    statistics: Statistics,
+    #[serde(default)]
+    csv: CsvConfig,
+    #[serde(default)]
+    buffs: BuffConfig,
+    #[serde(default)]
+    segments: Vec<Segment>,
+    #[serde(default)]
+    report: ReportConfig,
+    #[serde(default)]
+    usage: RawUsage,
+    #[serde(default)]
+    trend: Option<TrendBucket>,
+    #[serde(default)]
+    skills: SkillConfig,
+}
+
+#[derive(Deserialize, Default)]
+struct RawUsage {
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default)]
+    regions: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawClass {
+    role: Role,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RawWindow {
+    #[serde(default)]
+    start: Option<RawDate>,
+    #[serde(default)]
+    end: Option<RawDate>,
+}
+
+/// Patch boundaries can be written as an epoch, an ISO date string or a native TOML datetime
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDate {
+    Epoch(u64),
+    Iso(String),
+    Toml(toml::value::Datetime),
+}
+
+impl Config {
+    /// Read a `.json` configuration, or a TOML one for any other extension
+    pub fn load(filename: &str) -> Result<Config, String> {
+        let mut content = String::new();
+        File::open(filename)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .map_err(|e| format!("Unable to read config {}: {}", filename, e))?;
+        let mut raw: RawConfig = match Path::new(filename).extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&content)
+                .map_err(|e| format!("Unable to parse config {}: {}", filename, e))?,
+            _ => toml::from_str(&content)
+                .map_err(|e| format!("Unable to parse config {}: {}", filename, e))?,
+        };
+        let mut regions = RegionMap::new();
+        for (region, patches) in raw.regions {
+            let mut windows = HashMap::new();
+            for (patch_name, window) in patches {
+                let start = match window.start {
+                    Some(date) => date.to_epoch()?,
+                    None => u64::MIN,
+                };
+                let end = match window.end {
+                    Some(date) => date.to_epoch()?,
+                    None => u64::MAX,
+                };
+                windows.insert(patch_name, (start, end));
+            }
+            validate_windows(&region, &windows)?;
+            regions.insert(region, windows);
+        }
+        let classes = ClassRegistry::new(raw.classes)?;
+        for quantile in &raw.statistics.quantiles {
+            if !(0.0..=1.0).contains(quantile) {
+                return Err(format!("Quantile {} is not between 0 and 1", quantile));
+            }
+        }
+        // A quantile listed twice would be exported twice, and break the primary key of the database tables
+        let mut quantiles = Vec::new();
+        for &quantile in &raw.statistics.quantiles {
+            if !quantiles.contains(&quantile) {
+                quantiles.push(quantile);
+            }
+        }
+        raw.statistics.quantiles = quantiles;
+        if !(0.0..0.5).contains(&raw.statistics.trim) {
+            return Err(format!(
+                "Trim {} must be at least 0 and lower than 0.5",
+                raw.statistics.trim
+            ));
+        }
+        if raw.report.threshold < 0.0 {
+            return Err(format!("Report threshold {} is negative", raw.report.threshold));
+        }
+        for segment in &raw.segments {
+            if !(1..=100).contains(&segment.threshold) {
+                return Err(format!(
+                    "Segment threshold {} of buff {} is not between 1 and 100",
+                    segment.threshold, segment.buff
+                ));
+            }
+        }
+        let usage = UsageConfig {
+            timezone: match raw.usage.timezone {
+                Some(ref timezone) => parse_timezone(timezone)?,
+                None => Timezone::Fixed(FixedOffset::east_opt(0).unwrap()),
+            },
+            regions: raw
+                .usage
+                .regions
+                .iter()
+                .map(|(region, timezone)| Ok((region.clone(), parse_timezone(timezone)?)))
+                .collect::<Result<_, String>>()?,
+        };
+        Ok(Config {
+            regions,
+            classes,
+            rules: raw.rules,
+            statistics: raw.statistics,
+            csv: raw.csv,
+            buffs: raw.buffs,
+            segments: raw.segments,
+            report: raw.report,
+            usage,
+            trend: raw.trend,
+            skills: raw.skills,
+        })
+    }
+}
+
+impl ClassRegistry {
+    fn new(raw_classes: HashMap<String, RawClass>) -> Result<ClassRegistry, String> {
+        let mut registry = ClassRegistry {
+            roles: HashMap::new(),
+            aliases: HashMap::new(),
+        };
+        for (class, raw_class) in raw_classes {
+            for alias in raw_class.aliases.iter().chain(Some(&class)) {
+                if let Some(previous) = registry.aliases.insert(alias.clone(), class.clone()) {
+                    if previous != class {
+                        return Err(format!(
+                            "Class name {} is used by both {} and {}",
+                            alias, previous, class
+                        ));
+                    }
+                }
+            }
+            registry.roles.insert(class, raw_class.role);
+        }
+        Ok(registry)
+    }
+}
+
+impl RawDate {
+    fn to_epoch(&self) -> Result<u64, String> {
+        match self {
+            RawDate::Epoch(epoch) => Ok(*epoch),
+            RawDate::Iso(date) => parse_date(date),
+            RawDate::Toml(date) => parse_date(&date.to_string()),
+        }
+    }
+}
+
+fn parse_timezone(timezone: &str) -> Result<Timezone, String> {
+    if let Ok(offset) = timezone.parse() {
+        return Ok(Timezone::Fixed(offset));
+    }
+    timezone.parse().map(Timezone::Named).map_err(|_| {
+        format!(
+            "Invalid timezone {}, expected an offset such as +02:00 or a name such as Europe/Paris",
+            timezone
+        )
+    })
+}
+
+fn parse_date(date: &str) -> Result<u64, String> {
+    let timestamp = if let Ok(t) = DateTime::parse_from_rfc3339(date) {
+        t.timestamp()
+    } else if let Ok(t) = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S") {
+        t.and_utc().timestamp()
+    } else if let Ok(t) = NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S") {
+        t.and_utc().timestamp()
+    } else if let Ok(t) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        t.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp()
+    } else {
+        return Err(format!("Invalid date: {}", date));
+    };
+    if timestamp < 0 {
+        return Err(format!("Date before 1970: {}", date));
+    }
+    Ok(timestamp as u64)
+}
+
+// Patch windows of a region must follow each other without gap nor overlap
+fn validate_windows(region: &str, windows: &HashMap<String, (u64, u64)>) -> Result<(), String> {
+    let mut sorted: Vec<(&String, &(u64, u64))> = windows.iter().collect();
+    sorted.sort_by_key(|&(_, window)| *window);
+    for (patch_name, window) in &sorted {
+        if window.0 >= window.1 {
+            return Err(format!(
+                "Region {}: patch {} ends before it starts",
+                region, patch_name
+            ));
+        }
+    }
+    for pair in sorted.windows(2) {
+        let (previous_name, previous) = pair[0];
+        let (next_name, next) = pair[1];
+        if previous.1 > next.0 {
+            return Err(format!(
+                "Region {}: patches {} and {} overlap",
+                region, previous_name, next_name
+            ));
+        }
+        if previous.1 < next.0 {
+            return Err(format!(
+                "Region {}: gap between patches {} and {}",
+                region, previous_name, next_name
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_date, toml, validate_windows, Config, RawWindow};
+    use std::{collections::HashMap, env, fs, process};
+
+    // 2019-06-29T02:59:57Z
+    const TIMESTAMP: u64 = 1_561_777_197;
+
+    fn windows(windows: &[(&str, u64, u64)]) -> HashMap<String, (u64, u64)> {
+        windows
+            .iter()
+            .map(|&(name, start, end)| (name.to_string(), (start, end)))
+            .collect()
+    }
+
+    #[test]
+    fn windows_must_follow_each_other() {
+        let contiguous = windows(&[("79", 200, u64::MAX), ("old", u64::MIN, 100), ("76", 100, 200)]);
+        assert!(validate_windows("EU", &contiguous).is_ok());
+        assert!(validate_windows("EU", &windows(&[("all", u64::MIN, u64::MAX)])).is_ok());
+        let gap = windows(&[("old", u64::MIN, 100), ("79", 101, u64::MAX)]);
+        assert!(validate_windows("EU", &gap).unwrap_err().contains("gap between patches old and 79"));
+        let overlap = windows(&[("old", u64::MIN, 100), ("79", 99, u64::MAX)]);
+        assert!(validate_windows("EU", &overlap).unwrap_err().contains("patches old and 79 overlap"));
+        let reversed = windows(&[("old", u64::MIN, 100), ("79", 100, 100)]);
+        assert!(validate_windows("EU", &reversed).unwrap_err().contains("patch 79 ends before it starts"));
+    }
+
+    #[test]
+    fn dates_are_utc_unless_an_offset_is_given() {
+        assert_eq!(parse_date("2019-06-29T02:59:57Z"), Ok(TIMESTAMP));
+        assert_eq!(parse_date("2019-06-29T04:59:57+02:00"), Ok(TIMESTAMP));
+        assert_eq!(parse_date("2019-06-29T02:59:57"), Ok(TIMESTAMP));
+        assert_eq!(parse_date("2019-06-29 02:59:57"), Ok(TIMESTAMP));
+        assert_eq!(parse_date("2019-06-29"), Ok(TIMESTAMP - (2 * 3600 + 59 * 60 + 57)));
+        assert!(parse_date("1969-12-31").unwrap_err().contains("before 1970"));
+        assert!(parse_date("29/06/2019").unwrap_err().contains("Invalid date"));
+    }
+
+    #[test]
+    fn window_bounds_are_epochs_iso_strings_or_toml_dates() {
+        let raw: HashMap<String, RawWindow> = toml::from_str(
+            r#"
+            epoch = { start = 1561777197 }
+            iso = { start = "2019-06-29T02:59:57Z" }
+            toml_datetime = { start = 2019-06-29T02:59:57Z }
+            toml_local_datetime = { start = 2019-06-29T02:59:57 }
+            open = {}
+            "#,
+        )
+        .unwrap();
+        for name in &["epoch", "iso", "toml_datetime", "toml_local_datetime"] {
+            let start = raw[*name].start.as_ref().map(|date| date.to_epoch());
+            assert_eq!(start, Some(Ok(TIMESTAMP)), "{}", name);
+        }
+        assert!(raw["open"].start.is_none() && raw["open"].end.is_none());
+    }
+
+    #[test]
+    fn quantiles_listed_twice_are_kept_once() {
+        let shipped = fs::read_to_string("config.toml").unwrap();
+        let filename = env::temp_dir().join(format!("quantiles-{}.toml", process::id()));
+        fs::write(
+            &filename,
+            shipped.replace("quantiles = [0.1,", "quantiles = [0.9, 0.1, 0.9, 0.5, 0.1,"),
+        )
+        .unwrap();
+        let config = Config::load(filename.to_str().unwrap());
+        fs::remove_file(&filename).unwrap();
+        assert_eq!(
+            config.unwrap().statistics.quantiles,
+            vec![0.9, 0.1, 0.5, 0.25, 0.75, 0.95, 0.99]
+        );
+    }
+}
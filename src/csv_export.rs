@@ -0,0 +1,114 @@
+//! CSV export of the accepted members, one row per member.
+//! Rows are buffered per input file and written once the file is parsed, so that an invalid file writes nothing:
+//! each worker holds the rows of the file it parses, so memory grows with the size of the largest input file.
+
+extern crate csv;
+use config::Config;
+use parse::StatsLog;
+use process::{accepted_members, get_patch_name};
+use rules::buff_uptime;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    sync::Mutex,
+};
+
+/// One row per accepted member of an accepted encounter, shared by the worker threads
+pub struct CsvExport {
+    writer: Mutex<csv::Writer<File>>,
+}
+
+impl CsvExport {
+    /// Append to an existing file when `append` is set, the header is only written to an empty file.
+    /// The columns of an existing file must be the same, as the buffs are not part of the fingerprint.
+    pub fn new(filename: &str, append: bool, buffs: &[String]) -> Result<CsvExport, String> {
+        let mut header: Vec<String> = [
+            "timestamp",
+            "region",
+            "patch",
+            "area_id",
+            "boss_id",
+            "fight_duration",
+            "party_size",
+            "class",
+            "server",
+            "dps",
+        ]
+        .iter()
+        .map(|column| column.to_string())
+        .collect();
+        header.extend(buffs.iter().map(|buff| format!("buff_{}", buff)));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(filename)
+            .and_then(|file| Ok((file.metadata()?.len(), file)))
+            .map_err(|e| format!("Unable to open {}: {}", filename, e));
+        let (length, file) = file?;
+        if length > 0 {
+            let existing = csv::Reader::from_reader(&file)
+                .headers()
+                .map(|existing| existing.iter().eq(header.iter()))
+                .map_err(|e| format!("Unable to read {}: {}", filename, e))?;
+            if !existing {
+                return Err(format!(
+                    "{} has other columns than the buffs of the [csv] section, remove it to rebuild it",
+                    filename
+                ));
+            }
+        }
+        let mut writer = csv::Writer::from_writer(file);
+        if length == 0 {
+            writer
+                .write_record(&header)
+                .map_err(|e| format!("Unable to write {}: {}", filename, e))?;
+        }
+        Ok(CsvExport {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Rows of a whole file are written at once, so that an invalid file writes nothing
+    pub fn write(&self, rows: &[Vec<String>]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for row in rows {
+            writer.write_record(row)?;
+        }
+        writer.flush()
+    }
+}
+
+/// Same members as the ones kept by process::store: known class and readable dps
+pub fn rows(log: &StatsLog, config: &Config, rows: &mut Vec<Vec<String>>) {
+    let region = log.region();
+    let fight = &log.content;
+    let patch = match get_patch_name(&config.regions, region, fight.timestamp) {
+        Some(t) => t,
+        None => return,
+    };
+    for (member, class, dps) in accepted_members(log, &config.classes) {
+        let mut row = vec![
+            fight.timestamp.to_string(),
+            region.to_string(),
+            patch.clone(),
+            fight.area_id.to_string(),
+            fight.boss_id.to_string(),
+            fight.fight_duration.to_string(),
+            fight.members.len().to_string(),
+            class.clone(),
+            member.player_server.clone(),
+            dps.to_string(),
+        ];
+        row.extend(
+            config
+                .csv
+                .buffs
+                .iter()
+                .map(|buff| buff_uptime(member, buff).to_string()),
+        );
+        rows.push(row);
+    }
+}
@@ -0,0 +1,98 @@
+//! Value by value comparison of the text outputs of two runs.
+
+use glob::glob;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+/// Files of two outputs which differ
+#[derive(Default)]
+pub struct DiffSummary {
+    /// Files only in the old output
+    pub only_in_old: u32,
+    /// Files only in the new output
+    pub only_in_new: u32,
+    /// Files in both outputs with different values
+    pub changed: u32,
+}
+
+/// Compare the statistics (the `.txt` files) of two output directories and print the values which differ.
+/// Lines are compared by key, their order in the files does not matter.
+pub fn diff(old: &str, new: &str) -> Result<DiffSummary, String> {
+    let old_files = statistic_files(old)?;
+    let new_files = statistic_files(new)?;
+    let mut summary = DiffSummary::default();
+    for file in old_files.union(&new_files) {
+        if !new_files.contains(file) {
+            println!("Only in {}: {}", old, file);
+            summary.only_in_old += 1;
+            continue;
+        }
+        if !old_files.contains(file) {
+            println!("Only in {}: {}", new, file);
+            summary.only_in_new += 1;
+            continue;
+        }
+        let old_values = read_values(&Path::new(old).join(file))?;
+        let new_values = read_values(&Path::new(new).join(file))?;
+        if old_values == new_values {
+            continue;
+        }
+        println!("{}", file);
+        summary.changed += 1;
+        let keys: BTreeSet<&String> = old_values.keys().chain(new_values.keys()).collect();
+        for key in keys {
+            match (old_values.get(key), new_values.get(key)) {
+                (Some(old_value), Some(new_value)) if old_value != new_value => {
+                    println!("    {}: {} -> {}{}", key, old_value, new_value, change(old_value, new_value))
+                }
+                (Some(old_value), None) => println!("    {}: {} -> (none)", key, old_value),
+                (None, Some(new_value)) => println!("    {}: (none) -> {}", key, new_value),
+                _ => {}
+            }
+        }
+    }
+    Ok(summary)
+}
+
+// Paths of the `.txt` files, relative to the output directory
+fn statistic_files(directory: &str) -> Result<BTreeSet<String>, String> {
+    if !Path::new(directory).is_dir() {
+        return Err(format!("{} is not a directory", directory));
+    }
+    let search = format!("{}/**/*.txt", directory);
+    let mut files = BTreeSet::new();
+    for entry in glob(&search).expect("Failed to read glob pattern") {
+        let path = entry.map_err(|e| e.to_string())?;
+        if let Ok(relative) = path.strip_prefix(directory) {
+            files.insert(relative.to_string_lossy().into_owned());
+        }
+    }
+    Ok(files)
+}
+
+// "key:value" lines, a line without key (such as the clear times) has an empty key
+fn read_values(file: &Path) -> Result<BTreeMap<String, String>, String> {
+    let content =
+        fs::read_to_string(file).map_err(|e| format!("Unable to read {}: {}", file.display(), e))?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.rfind(':') {
+            Some(position) => (line[..position].to_string(), line[position + 1..].to_string()),
+            None => (String::new(), line.to_string()),
+        })
+        .collect())
+}
+
+// Relative change of numeric values
+fn change(old_value: &str, new_value: &str) -> String {
+    match (old_value.parse::<f64>(), new_value.parse::<f64>()) {
+        (Ok(old_number), Ok(new_number)) if old_number != 0.0 => {
+            format!(" ({:+.1}%)", (new_number - old_number) / old_number * 100.0)
+        }
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,105 @@
+//! Approximate count of distinct values, in a bounded size.
+
+use std::collections::BTreeMap;
+
+// 2^PRECISION registers, the standard error of the count is 1.04 / sqrt(2^PRECISION), about 3%
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// Approximate number of distinct hashes (HyperLogLog), its size is bounded whatever the number of hashes.
+/// Only the registers set are kept, so that small counts stay small in the snapshot.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct DistinctCount {
+    /// Register -> rank of the first set bit of the hashes falling in it
+    registers: BTreeMap<u16, u8>,
+}
+
+impl DistinctCount {
+    /// `hash` must be uniformly distributed, such as a sha256 digest
+    pub fn insert(&mut self, hash: u64) {
+        let register = (hash >> (64 - PRECISION)) as u16;
+        let rank = ((hash << PRECISION).leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        let value = self.registers.entry(register).or_insert(0);
+        *value = (*value).max(rank);
+    }
+
+    /// Count of the union of both sets
+    pub fn merge(&mut self, other: &DistinctCount) {
+        for (&register, &rank) in &other.registers {
+            let value = self.registers.entry(register).or_insert(0);
+            *value = (*value).max(rank);
+        }
+    }
+
+    /// Estimate of the number of distinct hashes inserted
+    pub fn count(&self) -> u64 {
+        let registers = REGISTERS as f64;
+        let zeros = REGISTERS - self.registers.len();
+        let sum = zeros as f64
+            + self
+                .registers
+                .values()
+                .map(|&rank| 2f64.powi(-i32::from(rank)))
+                .sum::<f64>();
+        let alpha = 0.7213 / (1.0 + 1.079 / registers);
+        let estimate = alpha * registers * registers / sum;
+        // Linear counting is more accurate while registers are still empty
+        if estimate <= 2.5 * registers && zeros > 0 {
+            (registers * (registers / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate sha2;
+    use self::sha2::{Digest, Sha256};
+    use super::DistinctCount;
+
+    fn hash(value: u64) -> u64 {
+        let digest = Sha256::digest(value.to_le_bytes());
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn count(values: std::ops::Range<u64>) -> DistinctCount {
+        let mut count = DistinctCount::default();
+        for value in values {
+            count.insert(hash(value));
+        }
+        count
+    }
+
+    #[test]
+    fn small_counts_are_close_to_exact() {
+        assert_eq!(DistinctCount::default().count(), 0);
+        assert_eq!(count(0..1).count(), 1);
+        assert_eq!(count(0..10).count(), 10);
+        // Duplicates are not counted
+        let mut twice = count(0..10);
+        for value in 0..10 {
+            twice.insert(hash(value));
+        }
+        assert_eq!(twice.count(), 10);
+    }
+
+    #[test]
+    fn large_counts_are_within_the_error_bound() {
+        for &n in &[1_000, 10_000, 100_000] {
+            let estimate = count(0..n).count() as f64;
+            // 3 standard errors
+            assert!((estimate - n as f64).abs() / (n as f64) < 0.1, "{} for {}", estimate, n);
+        }
+        assert!(count(0..100_000).registers.len() <= super::REGISTERS);
+    }
+
+    #[test]
+    fn merge_counts_the_union() {
+        let mut merged = count(0..6_000);
+        merged.merge(&count(4_000..10_000));
+        assert_eq!(merged.registers, count(0..10_000).registers);
+    }
+}
@@ -0,0 +1,595 @@
+//! Storage of the member dps and clear times, with their quantiles and descriptive statistics.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// How the values of a distribution (member dps, clear times) are kept in memory
+#[derive(Clone, Copy)]
+pub enum Backend {
+    /// Every value, quantiles are exact
+    Exact,
+    /// Log-scaled histogram, quantiles are within the given relative error
+    Sketch(f64),
+}
+
+impl Backend {
+    /// `exact` or `sketch`, the accuracy being only used by the sketch
+    pub fn new(name: &str, accuracy: f64) -> Result<Backend, String> {
+        match name {
+            "exact" => Ok(Backend::Exact),
+            "sketch" if accuracy > 0.0 && accuracy < 1.0 => Ok(Backend::Sketch(accuracy)),
+            "sketch" => Err(format!(
+                "Sketch accuracy must be between 0 and 1, got {}",
+                accuracy
+            )),
+            _ => Err(format!("Unknown backend {}, expected exact or sketch", name)),
+        }
+    }
+}
+
+/// How a quantile is picked among the sorted values
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum QuantileMethod {
+    /// Smallest value such that at least q of the values are lower or equal
+    NearestRank,
+    /// Linear interpolation between the two closest ranks (numpy default, R type 7)
+    Linear,
+}
+
+/// Descriptive statistics of a distribution
+pub struct Summary {
+    /// Number of values
+    pub count: u64,
+    /// Mean
+    pub mean: f64,
+    /// Sample standard deviation
+    pub std_dev: f64,
+    /// Smallest value
+    pub min: u64,
+    /// Largest value
+    pub max: u64,
+    /// Interquartile range, third quartile minus first quartile
+    pub iqr: u64,
+    /// Mean without the lowest and highest `trim` fraction of the values
+    pub trimmed_mean: f64,
+    /// Coefficient of variation, standard deviation over mean
+    pub cv: f64,
+}
+
+/// Median, and 10th and 90th percentiles: the time beaten by the fastest 10% of the clears, or the dps of the best 10%
+#[derive(Clone, Copy)]
+pub struct KeyQuantiles {
+    /// Median
+    pub median: u64,
+    /// 10th percentile
+    pub percentile_10: u64,
+    /// 90th percentile
+    pub percentile_90: u64,
+}
+
+/// Values of a member dps or a clear time, kept as chosen by the `Backend`
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Distribution {
+    /// Every value, unsorted until a quantile is asked.
+    /// Kept as u32 to halve the memory: dps and clear times in ms (up to 49 days) fit, larger values are clamped
+    Exact(Vec<u32>),
+    /// Values counted in buckets of bounded relative width
+    Sketch(Sketch),
+}
+
+impl Distribution {
+    /// Empty distribution of the backend
+    pub fn new(backend: Backend) -> Distribution {
+        match backend {
+            Backend::Exact => Distribution::Exact(Vec::new()),
+            Backend::Sketch(accuracy) => Distribution::Sketch(Sketch::new(accuracy)),
+        }
+    }
+
+    /// Add one value
+    pub fn add(&mut self, value: u64) {
+        match *self {
+            Distribution::Exact(ref mut values) => values.push(value.min(u64::from(u32::MAX)) as u32),
+            Distribution::Sketch(ref mut sketch) => sketch.add(value, 1),
+        }
+    }
+
+    /// Number of values added
+    pub fn len(&self) -> u64 {
+        match *self {
+            Distribution::Exact(ref values) => values.len() as u64,
+            Distribution::Sketch(ref sketch) => sketch.count,
+        }
+    }
+
+    /// No value was added
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Value of the given rank (0 being the smallest value), rank must be lower than len()
+    pub fn value_at(&mut self, rank: u64) -> u64 {
+        match *self {
+            Distribution::Exact(ref mut values) => {
+                values.sort_unstable();
+                u64::from(values[rank as usize])
+            }
+            Distribution::Sketch(ref sketch) => sketch.value_at(rank),
+        }
+    }
+
+    /// Quantile q (between 0 and 1), the distribution must not be empty
+    pub fn quantile(&mut self, q: f64, method: QuantileMethod) -> u64 {
+        let count = self.len();
+        pick(count, q, method, |rank| self.value_at(rank))
+    }
+
+    /// Median, 10th and 90th percentiles with the configured method, reported by every output but the legacy text files.
+    /// The distribution must not be empty
+    pub fn key_quantiles(&mut self, method: QuantileMethod) -> KeyQuantiles {
+        KeyQuantiles {
+            median: self.quantile(0.5, method),
+            percentile_10: self.quantile(0.1, method),
+            percentile_90: self.quantile(0.9, method),
+        }
+    }
+
+    /// Picks of the legacy `dps_median`, `dps_percentile_90` and `clear_time` text files, kept for compatibility:
+    /// values of rank len / 2, len * 0.1 and len * 0.9, which makes the maximum the 90th percentile of 10 values.
+    /// The distribution must not be empty
+    pub fn legacy_key_quantiles(&mut self) -> KeyQuantiles {
+        let count = self.len();
+        KeyQuantiles {
+            median: self.value_at(count / 2),
+            percentile_10: self.value_at((count as f32 * 0.1) as u64),
+            percentile_90: self.value_at((count as f32 * 0.9) as u64),
+        }
+    }
+
+    /// Mean, min, max and standard deviation are exact with both backends
+    pub fn summary(&mut self, trim: f64, method: QuantileMethod) -> Summary {
+        let iqr = self.quantile(0.75, method) - self.quantile(0.25, method);
+        let count = self.len();
+        let trimmed = (count as f64 * trim) as u64;
+        let (mean, m2, min, max, trimmed_mean) = match *self {
+            // Sorted by quantile()
+            Distribution::Exact(ref values) => {
+                let mean = values.iter().map(|&v| f64::from(v)).sum::<f64>() / count as f64;
+                let m2 = values.iter().map(|&v| (f64::from(v) - mean).powi(2)).sum::<f64>();
+                let kept = &values[trimmed as usize..(count - trimmed) as usize];
+                let trimmed_mean = kept.iter().map(|&v| f64::from(v)).sum::<f64>() / kept.len() as f64;
+                (
+                    mean,
+                    m2,
+                    u64::from(values[0]),
+                    u64::from(values[count as usize - 1]),
+                    trimmed_mean,
+                )
+            }
+            Distribution::Sketch(ref sketch) => (
+                sketch.mean,
+                sketch.m2,
+                sketch.min,
+                sketch.max,
+                sketch.trimmed_mean(trimmed),
+            ),
+        };
+        let std_dev = if count > 1 {
+            (m2 / (count - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+        Summary {
+            count,
+            mean,
+            std_dev,
+            min,
+            max,
+            iqr,
+            trimmed_mean,
+            cv: if mean > 0.0 { std_dev / mean } else { 0.0 },
+        }
+    }
+
+    /// Once merged with a sketch, the distribution becomes a sketch
+    pub fn merge(&mut self, other: Distribution) {
+        match other {
+            Distribution::Exact(other_values) => {
+                for value in other_values {
+                    self.add(u64::from(value));
+                }
+            }
+            Distribution::Sketch(mut other_sketch) => {
+                if let Distribution::Sketch(ref mut sketch) = *self {
+                    sketch.merge(other_sketch);
+                    return;
+                }
+                if let Distribution::Exact(ref values) = *self {
+                    for &value in values {
+                        other_sketch.add(u64::from(value), 1);
+                    }
+                }
+                *self = Distribution::Sketch(other_sketch);
+            }
+        }
+    }
+}
+
+/// Quantile q of the values of a histogram, value -> count, picked as `Distribution::quantile` does.
+/// The histogram must not be empty
+pub fn histogram_quantile(histogram: &HashMap<u32, u64>, q: f64, method: QuantileMethod) -> u64 {
+    let mut sorted: Vec<(u32, u64)> = histogram.iter().map(|(&value, &count)| (value, count)).collect();
+    sorted.sort_unstable();
+    let count = sorted.iter().map(|&(_, count)| count).sum();
+    pick(count, q, method, |rank| {
+        let mut seen = 0;
+        for &(value, count) in &sorted {
+            seen += count;
+            if rank < seen {
+                return u64::from(value);
+            }
+        }
+        0
+    })
+}
+
+// Quantile q among `count` sorted values, `value_at` giving the value of a rank
+fn pick<F>(count: u64, q: f64, method: QuantileMethod, mut value_at: F) -> u64
+where
+    F: FnMut(u64) -> u64,
+{
+    match method {
+        QuantileMethod::NearestRank => {
+            // q * count is not exact in floating point, 0.7 * 10 gives 7.000000000000001
+            let rank = (q * count as f64 - 1e-9).ceil() as u64;
+            value_at(rank.max(1).min(count) - 1)
+        }
+        QuantileMethod::Linear => {
+            let position = q * (count - 1) as f64;
+            let lower_rank = position.floor() as u64;
+            let lower = value_at(lower_rank) as f64;
+            if lower_rank + 1 >= count {
+                return lower as u64;
+            }
+            let upper = value_at(lower_rank + 1) as f64;
+            (lower + (position - lower_rank as f64) * (upper - lower)).round() as u64
+        }
+    }
+}
+
+/// Values are counted in buckets growing geometrically, so that any value of a bucket
+/// is within `accuracy` relative error of the bucket estimate (DDSketch).
+/// Memory only depends on the range of the values: about 850 buckets for 1 to 20 000 000 at 1%.
+/// Mean and sum of squared deviations are kept aside so that they stay exact.
+#[derive(Serialize, Deserialize)]
+pub struct Sketch {
+    accuracy: f64,
+    count: u64,
+    mean: f64,
+    m2: f64,
+    zeros: u64,
+    min: u64,
+    max: u64,
+    buckets: BTreeMap<i32, u64>,
+}
+
+impl Sketch {
+    fn new(accuracy: f64) -> Sketch {
+        Sketch {
+            accuracy,
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            zeros: 0,
+            min: u64::MAX,
+            max: 0,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    fn gamma(&self) -> f64 {
+        (1.0 + self.accuracy) / (1.0 - self.accuracy)
+    }
+
+    fn add(&mut self, value: u64, count: u64) {
+        if count == 0 {
+            return;
+        }
+        self.add_moments(count, value as f64, 0.0);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.insert(value, count);
+    }
+
+    fn insert(&mut self, value: u64, count: u64) {
+        if value == 0 {
+            self.zeros += count;
+            return;
+        }
+        let index = ((value as f64).ln() / self.gamma().ln()).ceil() as i32;
+        *(self.buckets.entry(index).or_insert(0)) += count;
+    }
+
+    // Combine the count, mean and squared deviations of another group of values (Chan et al.)
+    fn add_moments(&mut self, count: u64, mean: f64, m2: f64) {
+        let total = self.count + count;
+        let delta = mean - self.mean;
+        self.mean += delta * count as f64 / total as f64;
+        self.m2 += m2 + delta * delta * self.count as f64 * count as f64 / total as f64;
+        self.count = total;
+    }
+
+    // Mean of the values, without the `trimmed` lowest and highest ones
+    fn trimmed_mean(&self, trimmed: u64) -> f64 {
+        let gamma = self.gamma();
+        let (first, last) = (trimmed, self.count - trimmed);
+        let mut seen = 0;
+        let mut sum = 0.0;
+        let groups = Some((0, self.zeros)).into_iter().chain(
+            self.buckets
+                .iter()
+                .map(|(&index, &count)| (estimate(gamma, index).max(self.min).min(self.max), count)),
+        );
+        for (value, count) in groups {
+            let kept = (seen + count).min(last).saturating_sub(seen.max(first));
+            sum += value as f64 * kept as f64;
+            seen += count;
+        }
+        sum / (last - first) as f64
+    }
+
+    fn value_at(&self, rank: u64) -> u64 {
+        if rank < self.zeros {
+            return 0;
+        }
+        let gamma = self.gamma();
+        let mut seen = self.zeros;
+        for (&index, &count) in &self.buckets {
+            seen += count;
+            if rank < seen {
+                return estimate(gamma, index).max(self.min).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn merge(&mut self, other: Sketch) {
+        if other.count == 0 {
+            return;
+        }
+        self.add_moments(other.count, other.mean, other.m2);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        if other.accuracy != self.accuracy {
+            // Buckets do not line up, add the estimate of every other bucket instead
+            let gamma = other.gamma();
+            self.insert(0, other.zeros);
+            for (index, count) in other.buckets {
+                self.insert(estimate(gamma, index).max(other.min).min(other.max), count);
+            }
+            return;
+        }
+        self.zeros += other.zeros;
+        for (index, count) in other.buckets {
+            *(self.buckets.entry(index).or_insert(0)) += count;
+        }
+    }
+}
+
+// Middle of the bucket, in relative terms
+fn estimate(gamma: f64, index: i32) -> u64 {
+    (2.0 * gamma.powi(index) / (gamma + 1.0)).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{histogram_quantile, Backend, Distribution, QuantileMethod};
+    use std::collections::HashMap;
+
+    // Deterministic values spread over the range of the member dps, 0 included
+    fn values(count: u64, seed: u64) -> Vec<u64> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let exponent = (state >> 40) as f64 / (1u64 << 24) as f64 * 7.3;
+                if exponent < 0.1 {
+                    0
+                } else {
+                    10f64.powf(exponent) as u64
+                }
+            })
+            .collect()
+    }
+
+    fn distribution(backend: Backend, values: &[u64]) -> Distribution {
+        let mut distribution = Distribution::new(backend);
+        for &value in values {
+            distribution.add(value);
+        }
+        distribution
+    }
+
+    // Every percentile of the sketch is within `bound` relative error of the exact one
+    fn assert_within(sketch: &mut Distribution, exact: &mut Distribution, bound: f64) {
+        assert_eq!(sketch.len(), exact.len());
+        for percentile in 0..=100 {
+            let q = f64::from(percentile) / 100.0;
+            let expected = exact.quantile(q, QuantileMethod::NearestRank) as f64;
+            let value = sketch.quantile(q, QuantileMethod::NearestRank) as f64;
+            // Estimates are rounded to integers
+            assert!(
+                (value - expected).abs() <= bound * expected + 1.0,
+                "p{}: {} instead of {}",
+                percentile,
+                value,
+                expected
+            );
+        }
+    }
+
+    fn exact(values: &[u64]) -> Distribution {
+        distribution(Backend::Exact, values)
+    }
+
+    #[test]
+    fn nearest_rank_is_the_smallest_value_covering_the_quantile() {
+        let mut ten = exact(&[10, 9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        let nearest_rank = |distribution: &mut Distribution, q| distribution.quantile(q, QuantileMethod::NearestRank);
+        assert_eq!(nearest_rank(&mut ten, 0.0), 1);
+        assert_eq!(nearest_rank(&mut ten, 0.1), 1);
+        assert_eq!(nearest_rank(&mut ten, 0.11), 2);
+        assert_eq!(nearest_rank(&mut ten, 0.5), 5);
+        // 0.7 * 10 is 7.000000000000001 in floating point
+        assert_eq!(nearest_rank(&mut ten, 0.7), 7);
+        assert_eq!(nearest_rank(&mut ten, 0.9), 9);
+        assert_eq!(nearest_rank(&mut ten, 0.95), 10);
+        assert_eq!(nearest_rank(&mut ten, 1.0), 10);
+        let mut one = exact(&[42]);
+        for &q in &[0.0, 0.5, 1.0] {
+            assert_eq!(nearest_rank(&mut one, q), 42);
+        }
+    }
+
+    #[test]
+    fn linear_interpolates_between_the_closest_ranks() {
+        let mut four = exact(&[40, 10, 30, 20]);
+        let linear = |distribution: &mut Distribution, q| distribution.quantile(q, QuantileMethod::Linear);
+        assert_eq!(linear(&mut four, 0.0), 10);
+        // Position 0.75, between 10 and 20
+        assert_eq!(linear(&mut four, 0.25), 18);
+        assert_eq!(linear(&mut four, 0.5), 25);
+        assert_eq!(linear(&mut four, 0.9), 37);
+        assert_eq!(linear(&mut four, 1.0), 40);
+        let mut one = exact(&[42]);
+        for &q in &[0.0, 0.5, 1.0] {
+            assert_eq!(linear(&mut one, q), 42);
+        }
+    }
+
+    #[test]
+    fn key_quantiles_follow_the_method_but_the_legacy_ones() {
+        let mut ten = exact(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        let key = ten.key_quantiles(QuantileMethod::NearestRank);
+        assert_eq!((key.percentile_10, key.median, key.percentile_90), (1, 5, 9));
+        let key = ten.key_quantiles(QuantileMethod::Linear);
+        assert_eq!((key.percentile_10, key.median, key.percentile_90), (2, 6, 9));
+        // Ranks 5, 1 and 9: the maximum is the legacy 90th percentile
+        let key = ten.legacy_key_quantiles();
+        assert_eq!((key.percentile_10, key.median, key.percentile_90), (2, 6, 10));
+    }
+
+    #[test]
+    fn summary_trims_both_ends_and_uses_the_sample_deviation() {
+        let mut values = exact(&[100, 1, 4, 2, 3]);
+        let summary = values.summary(0.2, QuantileMethod::NearestRank);
+        assert_eq!((summary.count, summary.min, summary.max), (5, 1, 100));
+        assert_eq!(summary.mean, 22.0);
+        // Without 1 and 100
+        assert_eq!(summary.trimmed_mean, 3.0);
+        // Divided by count - 1
+        let std_dev = ((21f64.powi(2) + 20f64.powi(2) + 19f64.powi(2) + 18f64.powi(2) + 78f64.powi(2)) / 4.0).sqrt();
+        assert!((summary.std_dev - std_dev).abs() < 1e-9);
+        assert!((summary.cv - std_dev / 22.0).abs() < 1e-9);
+        // Quartiles 2 and 4
+        assert_eq!(summary.iqr, 2);
+        // Less than one value to trim on each side
+        assert_eq!(values.summary(0.1, QuantileMethod::NearestRank).trimmed_mean, 22.0);
+        let single = exact(&[7]).summary(0.0, QuantileMethod::Linear);
+        assert_eq!((single.mean, single.std_dev, single.trimmed_mean, single.iqr), (7.0, 0.0, 7.0, 0));
+    }
+
+    #[test]
+    fn exact_values_beyond_u32_are_clamped() {
+        let mut values = exact(&[1 << 40, 5]);
+        let summary = values.summary(0.0, QuantileMethod::NearestRank);
+        assert_eq!((summary.min, summary.max), (5, u64::from(u32::MAX)));
+    }
+
+    #[test]
+    fn histogram_quantiles_are_the_ones_of_the_values() {
+        let values: Vec<u64> = values(1_000, 3).iter().map(|value| value % 1001).collect();
+        let mut histogram = HashMap::new();
+        for &value in &values {
+            *(histogram.entry(value as u32).or_insert(0)) += 1;
+        }
+        let mut exact = exact(&values);
+        for &method in &[QuantileMethod::NearestRank, QuantileMethod::Linear] {
+            for percentile in 0..=100 {
+                let q = f64::from(percentile) / 100.0;
+                assert_eq!(histogram_quantile(&histogram, q, method), exact.quantile(q, method));
+            }
+        }
+    }
+
+    #[test]
+    fn sketch_quantiles_are_within_the_accuracy() {
+        let values = values(10_000, 1);
+        let mut exact = distribution(Backend::Exact, &values);
+        for &accuracy in &[0.001, 0.01, 0.05] {
+            let mut sketch = distribution(Backend::Sketch(accuracy), &values);
+            assert_within(&mut sketch, &mut exact, accuracy);
+        }
+    }
+
+    #[test]
+    fn sketch_merge_is_the_sketch_of_both_inputs() {
+        let (first, second) = (values(5_000, 1), values(3_000, 2));
+        let mut merged = distribution(Backend::Sketch(0.01), &first);
+        merged.merge(distribution(Backend::Sketch(0.01), &second));
+        let mut all = distribution(Backend::Sketch(0.01), &first);
+        for &value in &second {
+            all.add(value);
+        }
+        for percentile in 0..=100 {
+            let q = f64::from(percentile) / 100.0;
+            assert_eq!(
+                merged.quantile(q, QuantileMethod::NearestRank),
+                all.quantile(q, QuantileMethod::NearestRank)
+            );
+        }
+    }
+
+    #[test]
+    fn sketch_merge_across_accuracies_adds_up_the_errors() {
+        let (first, second) = (values(5_000, 1), values(3_000, 2));
+        let mut exact = distribution(Backend::Exact, &first);
+        exact.merge(distribution(Backend::Exact, &second));
+        let mut merged = distribution(Backend::Sketch(0.01), &first);
+        merged.merge(distribution(Backend::Sketch(0.05), &second));
+        assert_within(&mut merged, &mut exact, 1.01 * 1.05 - 1.0);
+        // The coarser sketch keeps its own buckets
+        let mut merged = distribution(Backend::Sketch(0.05), &second);
+        merged.merge(distribution(Backend::Sketch(0.01), &first));
+        assert_within(&mut merged, &mut exact, 1.01 * 1.05 - 1.0);
+    }
+
+    #[test]
+    fn exact_merged_with_a_sketch_becomes_a_sketch() {
+        let (first, second) = (values(2_000, 1), values(2_000, 2));
+        let mut exact = distribution(Backend::Exact, &first);
+        exact.merge(distribution(Backend::Exact, &second));
+        let mut merged = distribution(Backend::Exact, &first);
+        merged.merge(distribution(Backend::Sketch(0.01), &second));
+        match merged {
+            Distribution::Sketch(_) => (),
+            Distribution::Exact(_) => panic!("still exact"),
+        }
+        assert_within(&mut merged, &mut exact, 0.01);
+    }
+
+    #[test]
+    fn sketch_moments_are_exact() {
+        let (first, second) = (values(5_000, 1), values(3_000, 2));
+        let mut exact = distribution(Backend::Exact, &first);
+        exact.merge(distribution(Backend::Exact, &second));
+        let mut sketch = distribution(Backend::Sketch(0.01), &first);
+        sketch.merge(distribution(Backend::Sketch(0.05), &second));
+        let exact = exact.summary(0.0, QuantileMethod::NearestRank);
+        let sketch = sketch.summary(0.0, QuantileMethod::NearestRank);
+        assert_eq!((sketch.count, sketch.min, sketch.max), (exact.count, exact.min, exact.max));
+        assert!((sketch.mean - exact.mean).abs() <= 1e-9 * exact.mean);
+        assert!((sketch.std_dev - exact.std_dev).abs() <= 1e-9 * exact.std_dev);
+    }
+}
@@ -0,0 +1,161 @@
+//! Errors of the input files, and the reason they are reported under.
+
+use serde_json;
+use std::{error, fmt, io};
+
+/// Prefix of the serde error messages raised when a number cannot be read from a string
+pub const NUMERIC_COERCION: &str = "invalid number: ";
+
+/// What went wrong with an input file, lines and columns are the ones of the decompressed content
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// The file cannot be opened or read
+    Io(io::Error),
+    /// The content is not valid xz
+    Decompress(io::Error),
+    /// The decompressed content is not UTF-8
+    Utf8 {
+        /// Line, from 1
+        line: usize,
+        /// Column, from 1
+        column: usize,
+    },
+    /// The content is not JSON
+    JsonSyntax {
+        /// Line, from 1
+        line: usize,
+        /// Column, from 1
+        column: usize,
+        /// Message of the JSON parser
+        message: String,
+    },
+    /// The JSON does not have the expected fields and types
+    JsonSchema {
+        /// Line, from 1
+        line: usize,
+        /// Column, from 1
+        column: usize,
+        /// Message of the JSON parser
+        message: String,
+    },
+    /// A string field expected to hold a number does not
+    NumericCoercion {
+        /// Line, from 1
+        line: usize,
+        /// Column, from 1
+        column: usize,
+        /// The value which is not a number
+        value: String,
+    },
+}
+
+/// Error of an input file, which is then invalid as a whole
+#[derive(Debug)]
+pub struct ParseError {
+    /// Path of the input file
+    pub path: String,
+    /// What went wrong
+    pub kind: ErrorKind,
+}
+
+impl ParseError {
+    /// Error of the file at `path`
+    pub fn new(path: &str, kind: ErrorKind) -> ParseError {
+        ParseError {
+            path: path.to_string(),
+            kind,
+        }
+    }
+
+    /// Error of the JSON parser, classified by kind
+    pub fn from_json(path: &str, error: serde_json::Error) -> ParseError {
+        use self::serde_json::error::Category;
+        let line = error.line();
+        let column = error.column();
+        let message = error.to_string();
+        let location = format!(" at line {} column {}", line, column);
+        let message = message.trim_end_matches(&*location).to_string();
+        let kind = match error.classify() {
+            Category::Io => ErrorKind::Decompress(error.into()),
+            Category::Syntax if message == "invalid unicode code point" => {
+                ErrorKind::Utf8 { line, column }
+            }
+            Category::Syntax | Category::Eof => ErrorKind::JsonSyntax {
+                line,
+                column,
+                message,
+            },
+            Category::Data if message.starts_with(NUMERIC_COERCION) => ErrorKind::NumericCoercion {
+                line,
+                column,
+                value: message[NUMERIC_COERCION.len()..].to_string(),
+            },
+            Category::Data => ErrorKind::JsonSchema {
+                line,
+                column,
+                message,
+            },
+        };
+        ParseError::new(path, kind)
+    }
+
+    /// Short identifier of the kind of error, used in reports
+    pub fn reason(&self) -> &'static str {
+        match self.kind {
+            ErrorKind::Io(_) => "io_error",
+            ErrorKind::Decompress(_) => "decompression_error",
+            ErrorKind::Utf8 { .. } => "invalid_utf8",
+            ErrorKind::JsonSyntax { .. } => "invalid_json",
+            ErrorKind::JsonSchema { .. } => "unexpected_json_schema",
+            ErrorKind::NumericCoercion { .. } => "invalid_number",
+        }
+    }
+
+    /// True when the content of the file is invalid, as opposed to the file not being readable
+    pub fn is_invalid_content(&self) -> bool {
+        !matches!(self.kind, ErrorKind::Io(_))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::Io(ref e) => write!(f, "Unable to open {}: {}", self.path, e),
+            ErrorKind::Decompress(ref e) => write!(f, "Unable to decompress {}: {}", self.path, e),
+            ErrorKind::Utf8 { line, column } => write!(
+                f,
+                "UTF8 invalid {} at line {} column {}",
+                self.path, line, column
+            ),
+            ErrorKind::JsonSyntax {
+                line,
+                column,
+                ref message,
+            } => write!(
+                f,
+                "Invalid JSON in {} at line {} column {}: {}",
+                self.path, line, column, message
+            ),
+            ErrorKind::JsonSchema {
+                line,
+                column,
+                ref message,
+            } => write!(
+                f,
+                "Unexpected JSON structure in {} at line {} column {}: {}",
+                self.path, line, column, message
+            ),
+            ErrorKind::NumericCoercion {
+                line,
+                column,
+                ref value,
+            } => write!(
+                f,
+                "Invalid number {} in {} at line {} column {}",
+                value, self.path, line, column
+            ),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
@@ -0,0 +1,575 @@
+//! Text outputs, one file per statistic, fight, region and patch.
+
+use config::{BuffConfig, Config};
+use json_export::{self, JsonLayout};
+use process::{self, ExportClass, Fight, GlobalData};
+use quality::QualityReport;
+use serde_json;
+use sqlite_export::Database;
+use state::State;
+use segment::{self, ExportGroup, ExportSegment};
+use trend::{self, TrendSeries};
+use usage::{self, Usage};
+use std::{collections::{BTreeMap, HashMap}, fs, fs::File, io::prelude::*, path::Path};
+
+/// Write every output of the aggregates: data quality report, statistics, and the optional JSON and database outputs
+pub fn write_outputs(
+    target: &str,
+    dps_max: u32,
+    dps_steps: u32,
+    config: &Config,
+    json_layout: Option<&JsonLayout>,
+    database: Option<&Database>,
+    state: State,
+) {
+    write_quality(target, &state.quality);
+    export(
+        target.to_string(),
+        dps_max,
+        dps_steps,
+        state.data,
+        config,
+        json_layout,
+        database,
+    );
+}
+
+/// Write the text tree of the statistics under `target`, and the JSON documents and database tables when enabled
+pub fn export(
+    target: String,
+    dps_max: u32,
+    dps_steps: u32,
+    raw_data: GlobalData,
+    config: &Config,
+    json_layout: Option<&JsonLayout>,
+    mut database: Option<&Database>,
+) {
+    let class_registry = &config.classes;
+    let region_map = &config.regions;
+    let statistics = &config.statistics;
+    let mut buff_names = String::new();
+    for (buff, name) in config.buffs.names.iter().collect::<BTreeMap<_, _>>() {
+        buff_names.push_str(&format!("{}:{}\n", buff, name));
+    }
+    write_file(format!("{}/buff_names.txt", target), &buff_names);
+    for (region, fights) in &raw_data.usage {
+        let mut region_usage = Usage::default();
+        for (fight, fight_usage) in fights {
+            region_usage.add(fight_usage);
+            write_file(
+                format!("{}/usage/daily/{}/{}.txt", target, region, fight.to_str()),
+                &usage::daily_series(fight_usage),
+            );
+            write_file(
+                format!("{}/usage/hour_of_week/{}/{}.txt", target, region, fight.to_str()),
+                &usage::hour_of_week_series(fight_usage),
+            );
+        }
+        write_file(
+            format!("{}/usage/daily/{}.txt", target, region),
+            &usage::daily_series(&region_usage),
+        );
+        write_file(
+            format!("{}/usage/hour_of_week/{}.txt", target, region),
+            &usage::hour_of_week_series(&region_usage),
+        );
+    }
+    if let Some(Err(e)) = database.map(|database| database.begin_statistics()) {
+        println!("Unable to write the statistics to the database: {}", e);
+        database = None;
+    }
+    let mut class_global = HashMap::new();
+    let mut fight_documents = Vec::new();
+    let mut trends = raw_data.trends;
+    let mut segments = raw_data.segments;
+    for (fight_key, mut fight_data) in raw_data.fights {
+        let mut fight_document = json_export::FightDocument::new(&fight_key);
+        for (region, patch_data) in region_map {
+            for patch_name in patch_data.keys() {
+                let key = process::get_key(region, patch_name);
+                let time_data = match fight_data.remove(&key) {
+                    Some(t) => t,
+                    None => continue,
+                };
+                let result = process::export(time_data, statistics);
+                let buckets = trends
+                    .get_mut(&fight_key)
+                    .and_then(|fight_trends| fight_trends.remove(&key));
+                if let (Some(bucket), Some(buckets)) = (config.trend, buckets) {
+                    write_trend(
+                        &target,
+                        &fight_key,
+                        region,
+                        patch_name,
+                        trend::export(buckets, bucket, statistics.method),
+                    );
+                }
+                let segment_result = segments
+                    .get_mut(&fight_key)
+                    .and_then(|fight_segments| fight_segments.remove(&key))
+                    .map(|key_segments| segment::export(key_segments, statistics))
+                    .unwrap_or_default();
+                for (name, classes) in &segment_result {
+                    for (class, data) in classes {
+                        write_file(
+                            format!(
+                                "{}/buff_segment/{}/{}/{}/{}/{}.txt",
+                                target,
+                                name,
+                                fight_key.to_str(),
+                                class,
+                                region,
+                                patch_name
+                            ),
+                            &segment_lines(data),
+                        );
+                    }
+                }
+                if let Some(Err(e)) = database.map(|database| {
+                    database.insert_statistics(&fight_key, region, patch_name, &result, class_registry)
+                }) {
+                    // The statistics of the previous export are kept rather than a part of the current ones
+                    println!("Unable to write the statistics to the database: {}", e);
+                    if let Some(Err(e)) = database.map(|database| database.rollback_statistics()) {
+                        println!("Unable to roll back the statistics of the database: {}", e);
+                    }
+                    database = None;
+                }
+                if json_layout.is_some() {
+                    let mut entry = json_export::entry(
+                        region,
+                        patch_name,
+                        &result,
+                        class_registry,
+                        dps_max,
+                        dps_steps,
+                        &config.buffs.names,
+                    );
+                    entry.segments = json_export::segments(&segment_result);
+                    fight_document.entries.push(entry);
+                }
+                write_file(
+                    format!(
+                        "{target}/clear_time/{area_boss}/{region}/{patch_name}.txt",
+                        target = target,
+                        region = region,
+                        patch_name = patch_name,
+                        area_boss = fight_key.to_str()
+                    ),
+                    &format!(
+                        "{};{}",
+                        result.legacy_clear_time.median, result.legacy_clear_time.percentile_10
+                    ),
+                );
+                let mut result_clear_time_quantiles = String::new();
+                for (quantile, clear_time) in &result.clear_time_quantiles {
+                    result_clear_time_quantiles.push_str(&format!(
+                        "fastest_{}%:{}\n",
+                        process::quantile_name(*quantile),
+                        clear_time
+                    ));
+                }
+                write_file(
+                    format!(
+                        "{target}/clear_time_quantiles/{area_boss}/{region}/{patch_name}.txt",
+                        target = target,
+                        region = region,
+                        patch_name = patch_name,
+                        area_boss = fight_key.to_str()
+                    ),
+                    &result_clear_time_quantiles,
+                );
+                let mut result_clear_time_stats = String::new();
+                for (name, value) in process::summary_values(&result.clear_time_summary) {
+                    result_clear_time_stats.push_str(&format!("{}:{}\n", name, value));
+                }
+                write_file(
+                    format!(
+                        "{target}/clear_time_stats/{area_boss}/{region}/{patch_name}.txt",
+                        target = target,
+                        region = region,
+                        patch_name = patch_name,
+                        area_boss = fight_key.to_str()
+                    ),
+                    &result_clear_time_stats,
+                );
+                let mut result_stats = HashMap::new();
+                let mut result_quantiles = HashMap::new();
+                let mut result_percentile_90 = String::new();
+                let mut result_class = String::new();
+                let mut result_median = String::new();
+                for (class, data) in result.class {
+                    let mut result_dps = String::new();
+                    let mut dps = 0;
+                    while dps < dps_max {
+                        let count = data.stepped_dps.get(&dps).unwrap_or(&0_u32);
+                        result_dps.push_str(&format!("{}:{}\n", dps, count));
+                        dps += dps_steps;
+                    }
+                    write_file(
+                        format!(
+                            "{target}/dps/{area_boss}/{class}/{region}/{patch_name}.txt",
+                            class = class,
+                            target = target,
+                            region = region,
+                            patch_name = patch_name,
+                            area_boss = fight_key.to_str()
+                        ),
+                        &result_dps,
+                    );
+                    write_buffs(
+                        &target,
+                        &fight_key,
+                        &class,
+                        region,
+                        patch_name,
+                        &data,
+                        &config.buffs,
+                    );
+                    if config.skills.enabled {
+                        write_skills(&target, &fight_key, &class, region, patch_name, &data);
+                    }
+                    result_percentile_90
+                        .push_str(&format!("{}:{}\n", class, data.legacy_dps.percentile_90));
+                    result_class.push_str(&format!("{}:{}\n", class, data.count));
+                    *(class_global
+                        .entry(region)
+                        .or_insert(HashMap::new())
+                        .entry(patch_name)
+                        .or_insert(HashMap::new())
+                        .entry(class.clone())
+                        .or_insert(0)) += data.count;
+                    result_median.push_str(&format!("{}:{}\n", class, data.legacy_dps.median));
+                    for (quantile, dps) in &data.dps_quantiles {
+                        result_quantiles
+                            .entry(process::quantile_name(*quantile))
+                            .or_insert_with(String::new)
+                            .push_str(&format!("{}:{}\n", class, dps));
+                    }
+                    for (name, value) in process::summary_values(&data.dps_summary) {
+                        result_stats
+                            .entry(name)
+                            .or_insert_with(String::new)
+                            .push_str(&format!("{}:{}\n", class, value));
+                    }
+                }
+                let end_filename = format!(
+                    "/{area_boss}/{region}/{patch_name}.txt",
+                    area_boss = fight_key.to_str(),
+                    region = region,
+                    patch_name = patch_name,
+                );
+                write_file(
+                    format!("{}/dps_percentile_90/{}", target, end_filename),
+                    &result_percentile_90,
+                );
+                write_file(format!("{}/class/{}", target, end_filename), &result_class);
+                write_file(
+                    format!("{}/dps_median/{}", target, end_filename),
+                    &result_median,
+                );
+                for (quantile, result_quantile) in result_quantiles {
+                    write_file(
+                        format!("{}/dps_quantiles/p{}/{}", target, quantile, end_filename),
+                        &result_quantile,
+                    );
+                }
+                for (name, result_stat) in result_stats {
+                    write_file(
+                        format!("{}/dps_stats/{}/{}", target, name, end_filename),
+                        &result_stat,
+                    );
+                }
+                for &(kind, compositions) in &[
+                    ("roles", &result.role_compositions),
+                    ("classes", &result.class_compositions),
+                ] {
+                    let mut result_clears = String::new();
+                    let mut result_quantiles = HashMap::new();
+                    for (composition, data) in compositions {
+                        result_clears.push_str(&format!("{}:{}\n", composition, data.clears));
+                        for (quantile, clear_time) in &data.clear_time_quantiles {
+                            result_quantiles
+                                .entry(process::quantile_name(*quantile))
+                                .or_insert_with(String::new)
+                                .push_str(&format!("{}:{}\n", composition, clear_time));
+                        }
+                    }
+                    write_file(
+                        format!("{}/composition/{}/{}", target, kind, end_filename),
+                        &result_clears,
+                    );
+                    for (quantile, result_quantile) in result_quantiles {
+                        write_file(
+                            format!(
+                                "{}/composition_clear_time/{}/fastest_{}/{}",
+                                target, kind, quantile, end_filename
+                            ),
+                            &result_quantile,
+                        );
+                    }
+                }
+            }
+        }
+        if !fight_document.entries.is_empty() {
+            fight_document
+                .entries
+                .sort_by(|a, b| (&a.region, &a.patch).cmp(&(&b.region, &b.patch)));
+            fight_documents.push(fight_document);
+        }
+    }
+    if let Some(Err(e)) = database.map(|database| database.commit_statistics()) {
+        println!("Unable to write the statistics to the database: {}", e);
+    }
+    if let Some(json_layout) = json_layout {
+        fight_documents.sort_by_key(|document| (document.area_id, document.boss_id));
+        let population = json_export::population(&class_global, class_registry);
+        match *json_layout {
+            JsonLayout::PerFight => {
+                for document in &fight_documents {
+                    write_file(
+                        format!("{}/json/{}.json", target, document.fight),
+                        &json_export::to_string(document),
+                    );
+                }
+                write_file(
+                    format!("{}/json/population.json", target),
+                    &json_export::to_string(&json_export::PopulationDocument { population }),
+                );
+            }
+            JsonLayout::Consolidated => write_file(
+                format!("{}/aggregates.json", target),
+                &json_export::to_string(&json_export::Consolidated {
+                    fights: fight_documents,
+                    population,
+                }),
+            ),
+        }
+    }
+
+    for (region, patch_data) in region_map {
+        let class_global_region = match class_global.get(region) {
+            Some(t) => t,
+            None => continue,
+        };
+        for patch_name in patch_data.keys() {
+            let class_global_region_time = match class_global_region.get(patch_name) {
+                Some(t) => t,
+                None => continue,
+            };
+            let mut global_class_str = String::new();
+            let mut role_count = HashMap::new();
+            for (class, count) in class_global_region_time {
+                global_class_str.push_str(&format!("{}:{}\n", class, count));
+                if let Some(role) = class_registry.role(class) {
+                    *(role_count.entry(role).or_insert(0)) += count;
+                }
+            }
+            write_file(
+                format!("{}/class/{}/{}.txt", target, region, patch_name),
+                &global_class_str,
+            );
+            let mut global_role_str = String::new();
+            for (role, count) in role_count {
+                global_role_str.push_str(&format!("{}:{}\n", role.to_str(), count));
+            }
+            write_file(
+                format!("{}/role/{}/{}.txt", target, region, patch_name),
+                &global_role_str,
+            );
+        }
+    }
+}
+
+// One `buff:value` line per named buff for each statistic, and their histograms
+fn write_buffs(
+    target: &str,
+    fight: &Fight,
+    class: &str,
+    region: &str,
+    patch_name: &str,
+    data: &ExportClass,
+    buff_config: &BuffConfig,
+) {
+    let end_filename = format!("{}/{}/{}/{}.txt", fight.to_str(), class, region, patch_name);
+    let mut result_mean = String::new();
+    let mut result_median = String::new();
+    let mut result_share = String::new();
+    for (buff, uptime) in &data.buffs {
+        if !buff_config.names.contains_key(buff) {
+            continue;
+        }
+        result_mean.push_str(&format!("{}:{:.2}\n", buff, uptime.mean));
+        result_median.push_str(&format!("{}:{}\n", buff, uptime.median));
+        result_share.push_str(&format!(
+            "{}:{:.4}\n",
+            buff,
+            uptime.members as f64 / data.count as f64
+        ));
+        let mut result_histogram = String::new();
+        for bucket in (0..=100).step_by(10) {
+            let count = uptime.histogram.get(&bucket).unwrap_or(&0);
+            result_histogram.push_str(&format!("{}:{}\n", bucket, count));
+        }
+        write_file(
+            format!(
+                "{}/buff_uptime/histogram/{}/{}/{}/{}/{}.txt",
+                target,
+                fight.to_str(),
+                class,
+                buff,
+                region,
+                patch_name
+            ),
+            &result_histogram,
+        );
+    }
+    if result_mean.is_empty() {
+        return;
+    }
+    write_file(
+        format!("{}/buff_uptime/mean/{}", target, end_filename),
+        &result_mean,
+    );
+    write_file(
+        format!("{}/buff_uptime/median/{}", target, end_filename),
+        &result_median,
+    );
+    write_file(
+        format!("{}/buff_uptime/share/{}", target, end_filename),
+        &result_share,
+    );
+}
+
+// One `skill:value` line per skill for each statistic
+fn write_skills(
+    target: &str,
+    fight: &Fight,
+    class: &str,
+    region: &str,
+    patch_name: &str,
+    data: &ExportClass,
+) {
+    let end_filename = format!("{}/{}/{}/{}.txt", fight.to_str(), class, region, patch_name);
+    let mut results: Vec<(&str, String)> = vec![
+        ("members", String::new()),
+        ("damage_share", String::new()),
+        ("damage_percent", String::new()),
+        ("share_median", String::new()),
+        ("share_percentile_90", String::new()),
+        ("crit_rate", String::new()),
+        ("highest_crit", String::new()),
+        ("lowest_crit", String::new()),
+    ];
+    for (skill, details) in &data.skills {
+        let values = [
+            details.members.to_string(),
+            format!("{:.2}", details.damage_share),
+            format!("{:.2}", details.damage_percent),
+            format!("{:.1}", details.share_median),
+            format!("{:.1}", details.share_percentile_90),
+            format!("{:.2}", details.crit_rate),
+            details.highest_crit.to_string(),
+            details.lowest_crit.to_string(),
+        ];
+        for ((_, result), value) in results.iter_mut().zip(values.iter()) {
+            result.push_str(&format!("{}:{}\n", skill, value));
+        }
+    }
+    for (name, result) in results {
+        write_file(
+            format!("{}/skill_damage/{}/{}", target, name, end_filename),
+            &result,
+        );
+    }
+}
+
+// `count:<with>;<without>` (0 for an empty group), then `<statistic>:<with>;<without>;<with - without>`, `-` for a missing value
+fn segment_lines(data: &ExportSegment) -> String {
+    let value = |value: Option<u32>| value.map_or_else(|| "-".to_string(), |value| value.to_string());
+    let count = |group: &Option<ExportGroup>| group.as_ref().map_or(0, |group| group.count);
+    let mut result = format!("count:{};{}\n", count(&data.with), count(&data.without));
+    for segment_value in data.values() {
+        result.push_str(&format!(
+            "{}:{};{};{}\n",
+            segment_value.name,
+            value(segment_value.with),
+            value(segment_value.without),
+            segment_value
+                .difference()
+                .map_or_else(|| "-".to_string(), |difference| format!("{:+}", difference))
+        ));
+    }
+    result
+}
+
+// Lines ordered by bucket, one file per series
+fn write_trend(target: &str, fight: &Fight, region: &str, patch_name: &str, series: TrendSeries) {
+    let mut result_clear_time = String::new();
+    for (label, median, fastest_10) in series.clear_time {
+        result_clear_time.push_str(&format!("{}:{};{}\n", label, median, fastest_10));
+    }
+    write_file(
+        format!(
+            "{}/trend/clear_time/{}/{}/{}.txt",
+            target,
+            fight.to_str(),
+            region,
+            patch_name
+        ),
+        &result_clear_time,
+    );
+    for (class, points) in series.classes {
+        let mut result_class = String::new();
+        let mut result_median = String::new();
+        let mut result_percentile_90 = String::new();
+        for (label, count, median, percentile_90) in points {
+            result_class.push_str(&format!("{}:{}\n", label, count));
+            result_median.push_str(&format!("{}:{}\n", label, median));
+            result_percentile_90.push_str(&format!("{}:{}\n", label, percentile_90));
+        }
+        let write = |name: &str, content: &String| {
+            write_file(
+                format!(
+                    "{}/trend/{}/{}/{}/{}/{}.txt",
+                    target,
+                    name,
+                    fight.to_str(),
+                    class,
+                    region,
+                    patch_name
+                ),
+                content,
+            )
+        };
+        write("class", &result_class);
+        write("dps_median", &result_median);
+        write("dps_percentile_90", &result_percentile_90);
+    }
+}
+
+/// Print the rejected encounters and unknown classes, and write `unknown_classes.txt` and `data_quality.json`
+fn write_quality(target: &str, quality: &QualityReport) {
+    quality.print();
+    let mut unknown_classes = String::new();
+    for (class, count) in &quality.unknown_classes {
+        unknown_classes.push_str(&format!("{}:{}\n", class, count));
+    }
+    write_file(format!("{}/unknown_classes.txt", target), &unknown_classes);
+    write_file(
+        format!("{}/data_quality.json", target),
+        &serde_json::to_string_pretty(quality).unwrap(),
+    );
+}
+
+fn write_file(name: String, content: &String) {
+    let path = Path::new(&name);
+    let display = path.display();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .unwrap_or_else(|e| panic!("couldn't create the directory of {}: {}", display, e));
+    }
+    let mut file = File::create(path).unwrap_or_else(|_| panic!("couldn't create {}", display));
+    file.write_all(content.as_bytes())
+        .unwrap_or_else(|_| panic!("couldn't write to {}", display));
+}
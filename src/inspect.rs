@@ -0,0 +1,61 @@
+//! Encounter by encounter view of one input file.
+
+use chrono::DateTime;
+use config::Config;
+use error::ParseError;
+use parse::{self, Parsed, StatsLog};
+use process::{get_patch_name, Fight};
+use quality::NO_PATCH;
+use rules::find_rejecting_rule;
+
+/// Print every encounter of an input file, whether it is accepted or why it is rejected,
+/// and the members left out of the statistics
+pub fn inspect(filename: &str, config: &Config) -> Result<(), ParseError> {
+    let mut index = 0;
+    // The rules are applied here, to print the rejected encounters along with the accepted ones
+    parse::read_file(filename, &[], |one_fight| {
+        if let Parsed::Accepted(log) = one_fight {
+            print_encounter(index, &log, config);
+            index += 1;
+        }
+    })?;
+    println!("{} encounters", index);
+    Ok(())
+}
+
+fn print_encounter(index: usize, log: &StatsLog, config: &Config) {
+    let region = log.region();
+    let fight = &log.content;
+    let patch = get_patch_name(&config.regions, region, fight.timestamp);
+    let status = match (find_rejecting_rule(&config.rules, log), &patch) {
+        (Some(rule), _) => format!("rejected ({})", rule.reason()),
+        (None, None) => "rejected (no_patch_window)".to_string(),
+        (None, Some(_)) => "accepted".to_string(),
+    };
+    let date = DateTime::from_timestamp(fight.timestamp as i64, 0)
+        .map_or_else(|| fight.timestamp.to_string(), |date| date.to_rfc3339());
+    println!(
+        "#{} {} {} patch {} {}: {} s, {} members, {}",
+        index,
+        Fight::new(fight.area_id, fight.boss_id).to_str(),
+        region,
+        patch.as_deref().unwrap_or(NO_PATCH),
+        date,
+        fight.fight_duration,
+        fight.members.len(),
+        status
+    );
+    for member in &fight.members {
+        let skipped = if config.classes.get(&member.player_class).is_none() {
+            " (unknown_class)"
+        } else if member.player_dps.parse::<u32>().is_err() {
+            " (unparsable_dps)"
+        } else {
+            ""
+        };
+        println!(
+            "    {} {} dps {}{}",
+            member.player_class, member.player_server, member.player_dps, skipped
+        );
+    }
+}
@@ -0,0 +1,466 @@
+//! JSON export of the statistics, one document per fight or one per population.
+
+extern crate serde;
+use self::serde::Serialize;
+use config::ClassRegistry;
+use distribution::Summary;
+use process::{quantile_name, ExportComposition, ExportResult, Fight};
+use segment::{ExportGroup, ExportSegment};
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+
+/// Bumped on any incompatible change of the documents below
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Files the JSON documents are written to, `--json <layout>`
+pub enum JsonLayout {
+    /// One document per fight, in `json/<fight>.json`, plus `json/population.json`
+    PerFight,
+    /// Everything in aggregates.json
+    Consolidated,
+}
+
+impl JsonLayout {
+    /// `per-fight` or `consolidated`
+    pub fn new(name: &str) -> Result<JsonLayout, String> {
+        match name {
+            "per-fight" => Ok(JsonLayout::PerFight),
+            "consolidated" => Ok(JsonLayout::Consolidated),
+            _ => Err(format!(
+                "Unknown JSON layout {}, expected per-fight or consolidated",
+                name
+            )),
+        }
+    }
+}
+
+/// Statistics of one fight in every region and patch
+#[derive(Serialize)]
+pub struct FightDocument {
+    /// `<area>-<boss>`
+    pub fight: String,
+    /// Area of the fight
+    pub area_id: u32,
+    /// Boss of the fight
+    pub boss_id: u32,
+    /// Sorted by region then patch
+    pub entries: Vec<Entry>,
+}
+
+/// Statistics of one fight, region and patch
+#[derive(Serialize)]
+pub struct Entry {
+    /// Region
+    pub region: String,
+    /// Patch, named in the configuration
+    pub patch: String,
+    /// Clear times
+    pub clear_time: ClearTime,
+    /// Canonical class -> dps of its members
+    pub classes: BTreeMap<String, ClassEntry>,
+    /// Clear times per party composition
+    pub compositions: Compositions,
+    /// `<buff>-<threshold>` -> class -> dps of the members with and without the buff, see `segments`
+    pub segments: BTreeMap<String, BTreeMap<String, SegmentEntry>>,
+}
+
+/// Dps of the members of a class with and without a buff
+#[derive(Serialize)]
+pub struct SegmentEntry {
+    /// Members with an uptime of at least the threshold, null without such member
+    pub with: Option<SegmentGroup>,
+    /// Members below the threshold, null without such member
+    pub without: Option<SegmentGroup>,
+    /// Statistic -> with - without, only when both groups have members
+    pub difference: BTreeMap<String, i64>,
+}
+
+/// Dps of a group of members
+#[derive(Serialize)]
+pub struct SegmentGroup {
+    /// Members
+    pub count: u64,
+    /// Median dps
+    pub dps_median: u32,
+    /// 90th percentile dps
+    pub dps_percentile_90: u32,
+    /// `p<N>` -> dps
+    pub quantiles: BTreeMap<String, u32>,
+}
+
+/// Clears per party composition, encounters with a member of unknown class are left out
+#[derive(Serialize)]
+pub struct Compositions {
+    /// `<tanks>-<healers>-<dps>` -> clears
+    pub roles: BTreeMap<String, CompositionEntry>,
+    /// Classes sorted and joined by `+` -> clears
+    pub classes: BTreeMap<String, CompositionEntry>,
+}
+
+/// Clear times of one composition
+#[derive(Serialize)]
+pub struct CompositionEntry {
+    /// Clears of the composition
+    pub clears: u64,
+    /// Seconds
+    pub clear_time_median: u64,
+    /// `fastest_<N>%` -> seconds
+    pub clear_time_quantiles: BTreeMap<String, u64>,
+}
+
+/// Clear times of a fight, in seconds
+#[derive(Serialize)]
+pub struct ClearTime {
+    /// Median
+    pub median: u64,
+    /// Time beaten by the fastest 10% of the clears
+    pub fastest_10: u64,
+    /// `fastest_<N>%` -> seconds
+    pub quantiles: BTreeMap<String, u64>,
+    /// Descriptive statistics
+    pub stats: Stats,
+}
+
+/// Dps, buffs and skills of the members of a class
+#[derive(Serialize)]
+pub struct ClassEntry {
+    /// Role of the class, null for a class without role in the configuration
+    pub role: Option<&'static str>,
+    /// Members
+    pub count: u64,
+    /// Median dps
+    pub dps_median: u32,
+    /// 90th percentile dps
+    pub dps_percentile_90: u32,
+    /// `p<N>` -> dps
+    pub quantiles: BTreeMap<String, u32>,
+    /// Descriptive statistics of the dps
+    pub stats: Stats,
+    /// Members per dps bucket
+    pub histogram: Histogram,
+    /// Buff id -> uptime of the members
+    pub buffs: BTreeMap<String, BuffEntry>,
+    /// Members with a skill log
+    pub skill_members: u64,
+    /// Skill id -> damage of the skill
+    pub skills: BTreeMap<String, SkillEntry>,
+}
+
+/// Damage of a skill, shares and crit rate in %
+#[derive(Serialize)]
+pub struct SkillEntry {
+    /// Members using the skill
+    pub members: u64,
+    /// Over the damage of all the skills of the class
+    pub damage_share: f64,
+    /// Mean of the damage percents sent by the meter
+    pub damage_percent: f64,
+    /// Over the damage of a member using the skill
+    pub share_median: f64,
+    /// 90th percentile of the share over the damage of a member using the skill
+    pub share_percentile_90: f64,
+    /// Hits of the skill
+    pub hits: u64,
+    /// Crit rate weighted by hits
+    pub crit_rate: f64,
+    /// Highest crit seen
+    pub highest_crit: u64,
+    /// Lowest crit seen, 0 for a skill which never crit
+    pub lowest_crit: u64,
+}
+
+/// Uptime of a buff, a member without the buff has an uptime of 0
+#[derive(Serialize)]
+pub struct BuffEntry {
+    /// Name from the `[buffs.names]` section of the configuration
+    pub name: Option<String>,
+    /// Members with an uptime above 0
+    pub members: u64,
+    /// Mean uptime, members without the buff included
+    pub mean: f64,
+    /// Median uptime, members without the buff included
+    pub median: u32,
+    /// Lower bound of the 10% wide uptime bucket -> members, empty buckets omitted
+    pub histogram: BTreeMap<u32, u64>,
+}
+
+/// Descriptive statistics, see `distribution::Summary`
+#[derive(Serialize)]
+pub struct Stats {
+    /// Number of values
+    pub count: u64,
+    /// Mean
+    pub mean: f64,
+    /// Sample standard deviation
+    pub std_dev: f64,
+    /// Smallest value
+    pub min: u64,
+    /// Largest value
+    pub max: u64,
+    /// Third quartile minus first quartile
+    pub iqr: u64,
+    /// Mean without the lowest and highest `trim` fraction of the values
+    pub trimmed_mean: f64,
+    /// Coefficient of variation
+    pub cv: f64,
+}
+
+/// Number of members per dps bucket, buckets without members are omitted
+#[derive(Serialize)]
+pub struct Histogram {
+    /// Width of a bucket, `--dps-steps`
+    pub step: u32,
+    /// Lower bound of the bucket -> members
+    pub counts: BTreeMap<u32, u32>,
+}
+
+/// Members per class and role of a region and patch
+#[derive(Serialize, Default)]
+pub struct Population {
+    /// Canonical class -> members
+    pub classes: BTreeMap<String, u64>,
+    /// Role -> members
+    pub roles: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+struct Versioned<'a, T: 'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    document: &'a T,
+}
+
+/// `population.json` of the per-fight layout
+#[derive(Serialize)]
+pub struct PopulationDocument {
+    /// region -> patch
+    pub population: BTreeMap<String, BTreeMap<String, Population>>,
+}
+
+/// `aggregates.json` of the consolidated layout
+#[derive(Serialize)]
+pub struct Consolidated {
+    /// Sorted by fight
+    pub fights: Vec<FightDocument>,
+    /// region -> patch
+    pub population: BTreeMap<String, BTreeMap<String, Population>>,
+}
+
+/// Pretty printed document, with the `schema_version`
+pub fn to_string<T: Serialize>(document: &T) -> String {
+    serde_json::to_string_pretty(&Versioned {
+        schema_version: SCHEMA_VERSION,
+        document,
+    })
+    .unwrap()
+}
+
+impl FightDocument {
+    /// Document without entries
+    pub fn new(fight: &Fight) -> FightDocument {
+        FightDocument {
+            fight: fight.to_str(),
+            area_id: fight.area_id,
+            boss_id: fight.boss_id,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// Must be called before the classes of the result are consumed
+pub fn entry(
+    region: &str,
+    patch: &str,
+    result: &ExportResult,
+    class_registry: &ClassRegistry,
+    dps_max: u32,
+    dps_steps: u32,
+    buff_names: &HashMap<String, String>,
+) -> Entry {
+    let clear_time = ClearTime {
+        median: result.clear_time_median,
+        fastest_10: result.clear_time_fastest_10,
+        quantiles: result
+            .clear_time_quantiles
+            .iter()
+            .map(|&(quantile, value)| (format!("fastest_{}%", quantile_name(quantile)), value))
+            .collect(),
+        stats: Stats::new(&result.clear_time_summary),
+    };
+    let mut classes = BTreeMap::new();
+    for (class, data) in &result.class {
+        let counts = data
+            .stepped_dps
+            .iter()
+            .filter(|&(&dps, _)| dps < dps_max)
+            .map(|(&dps, &count)| (dps, count))
+            .collect();
+        classes.insert(
+            class.clone(),
+            ClassEntry {
+                role: class_registry.role(class).map(|role| role.to_str()),
+                count: data.count,
+                dps_median: data.dps_median,
+                dps_percentile_90: data.dps_percentile_90,
+                quantiles: data
+                    .dps_quantiles
+                    .iter()
+                    .map(|&(quantile, value)| (format!("p{}", quantile_name(quantile)), value))
+                    .collect(),
+                stats: Stats::new(&data.dps_summary),
+                histogram: Histogram {
+                    step: dps_steps,
+                    counts,
+                },
+                buffs: data
+                    .buffs
+                    .iter()
+                    .map(|(buff, uptime)| {
+                        let entry = BuffEntry {
+                            name: buff_names.get(buff).cloned(),
+                            members: uptime.members,
+                            mean: uptime.mean,
+                            median: uptime.median,
+                            histogram: uptime.histogram.clone(),
+                        };
+                        (buff.clone(), entry)
+                    })
+                    .collect(),
+                skill_members: data.skill_members,
+                skills: data
+                    .skills
+                    .iter()
+                    .map(|(skill, details)| {
+                        let entry = SkillEntry {
+                            members: details.members,
+                            damage_share: details.damage_share,
+                            damage_percent: details.damage_percent,
+                            share_median: details.share_median,
+                            share_percentile_90: details.share_percentile_90,
+                            hits: details.hits,
+                            crit_rate: details.crit_rate,
+                            highest_crit: details.highest_crit,
+                            lowest_crit: details.lowest_crit,
+                        };
+                        (skill.clone(), entry)
+                    })
+                    .collect(),
+            },
+        );
+    }
+    Entry {
+        region: region.to_string(),
+        patch: patch.to_string(),
+        clear_time,
+        classes,
+        compositions: Compositions {
+            roles: composition_entries(&result.role_compositions),
+            classes: composition_entries(&result.class_compositions),
+        },
+        segments: BTreeMap::new(),
+    }
+}
+
+/// Entries of the buff segments of one fight, region and patch
+pub fn segments(
+    segments: &BTreeMap<String, BTreeMap<String, ExportSegment>>,
+) -> BTreeMap<String, BTreeMap<String, SegmentEntry>> {
+    segments
+        .iter()
+        .map(|(name, classes)| {
+            let classes = classes
+                .iter()
+                .map(|(class, data)| {
+                    let entry = SegmentEntry {
+                        with: data.with.as_ref().map(SegmentGroup::new),
+                        without: data.without.as_ref().map(SegmentGroup::new),
+                        difference: data
+                            .values()
+                            .into_iter()
+                            .filter_map(|value| {
+                                let difference = value.difference()?;
+                                Some((value.name, difference))
+                            })
+                            .collect(),
+                    };
+                    (class.clone(), entry)
+                })
+                .collect();
+            (name.clone(), classes)
+        })
+        .collect()
+}
+
+impl SegmentGroup {
+    fn new(group: &ExportGroup) -> SegmentGroup {
+        SegmentGroup {
+            count: group.count,
+            dps_median: group.dps_median,
+            dps_percentile_90: group.dps_percentile_90,
+            quantiles: group
+                .dps_quantiles
+                .iter()
+                .map(|&(quantile, value)| (format!("p{}", quantile_name(quantile)), value))
+                .collect(),
+        }
+    }
+}
+
+fn composition_entries(
+    compositions: &BTreeMap<String, ExportComposition>,
+) -> BTreeMap<String, CompositionEntry> {
+    compositions
+        .iter()
+        .map(|(composition, data)| {
+            let entry = CompositionEntry {
+                clears: data.clears,
+                clear_time_median: data.clear_time_median,
+                clear_time_quantiles: data
+                    .clear_time_quantiles
+                    .iter()
+                    .map(|&(quantile, value)| (format!("fastest_{}%", quantile_name(quantile)), value))
+                    .collect(),
+            };
+            (composition.clone(), entry)
+        })
+        .collect()
+}
+
+/// region -> patch -> class -> members, as computed by the text export
+pub fn population(
+    class_global: &HashMap<&String, HashMap<&String, HashMap<String, u64>>>,
+    class_registry: &ClassRegistry,
+) -> BTreeMap<String, BTreeMap<String, Population>> {
+    let mut result: BTreeMap<String, BTreeMap<String, Population>> = BTreeMap::new();
+    for (region, patches) in class_global {
+        for (patch, classes) in patches {
+            let population = result
+                .entry(region.to_string())
+                .or_default()
+                .entry(patch.to_string())
+                .or_default();
+            for (class, &count) in classes {
+                population.classes.insert(class.clone(), count);
+                if let Some(role) = class_registry.role(class) {
+                    *(population.roles.entry(role.to_str().to_string()).or_insert(0)) += count;
+                }
+            }
+        }
+    }
+    result
+}
+
+impl Stats {
+    fn new(summary: &Summary) -> Stats {
+        Stats {
+            count: summary.count,
+            mean: summary.mean,
+            std_dev: summary.std_dev,
+            min: summary.min,
+            max: summary.max,
+            iqr: summary.iqr,
+            trimmed_mean: summary.trimmed_mean,
+            cv: summary.cv,
+        }
+    }
+}
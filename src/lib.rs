@@ -0,0 +1,43 @@
+//! Statistics over the encounter logs uploaded by Shinra meter.
+//!
+//! The pipeline, as run by `analyse::analyse`:
+//! - `parse::read_file` streams the fights of a `.xz` log file, rejecting the ones matching a `rules::Rule`
+//! - `process::store` adds an accepted fight to the `process::GlobalData` aggregates
+//! - `process::export` computes the statistics of one fight, region and patch
+//! - `export`, `json_export`, `csv_export`, `sqlite_export` and `parquet_export` write them
+//!
+//! Everything is configured by a `config::Config`, loaded from `config.toml`.
+
+#![warn(missing_docs)]
+
+extern crate chrono;
+extern crate glob;
+extern crate num_cpus;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate threadpool;
+
+pub mod analyse;
+pub mod config;
+pub mod csv_export;
+pub mod diff;
+pub mod distinct;
+pub mod distribution;
+pub mod error;
+pub mod export;
+pub mod inspect;
+pub mod json_export;
+mod manifest;
+pub mod parquet_export;
+pub mod parse;
+pub mod process;
+pub mod quality;
+pub mod quarantine;
+pub mod report;
+pub mod rules;
+pub mod segment;
+pub mod sqlite_export;
+pub mod state;
+pub mod trend;
+pub mod usage;
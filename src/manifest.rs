@@ -0,0 +1,164 @@
+//! List of the input files already aggregated, to only parse the new ones on incremental runs.
+
+extern crate sha2;
+use self::sha2::{Digest, Sha256};
+use serde_json;
+use std::{
+    collections::HashMap,
+    fs,
+    fs::File,
+    io,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+/// Files already aggregated in the persisted state, by path relative to the source directory
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    /// Relative path -> file as it was aggregated
+    pub files: HashMap<String, FileEntry>,
+}
+
+/// What identifies the content of a file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileEntry {
+    /// Bytes
+    pub size: u64,
+    /// Modification time, unix timestamp in seconds
+    pub mtime: u64,
+    /// SHA-256 of the content, in hex
+    pub hash: String,
+}
+
+/// A file of the source directory compared with the manifest
+pub enum FileStatus {
+    /// Not in the manifest
+    New,
+    /// Same size and modification time, the content is not read
+    Unchanged,
+    /// Same content, but the size or modification time differs from the manifest
+    Touched(FileEntry),
+    /// Other content, the aggregates must be rebuilt
+    Changed,
+}
+
+impl Manifest {
+    /// No file
+    pub fn new() -> Manifest {
+        Manifest {
+            files: HashMap::new(),
+        }
+    }
+
+    /// Read a manifest written by `save`
+    pub fn load(filename: &str) -> Result<Manifest, String> {
+        let file = File::open(filename).map_err(|e| format!("Unable to open {}: {}", filename, e))?;
+        serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|e| format!("Unable to parse {}: {}", filename, e))
+    }
+
+    /// Write the manifest as pretty printed json
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let file = File::create(filename).map_err(|e| format!("Unable to create {}: {}", filename, e))?;
+        serde_json::to_writer_pretty(io::BufWriter::new(file), self)
+            .map_err(|e| format!("Unable to write {}: {}", filename, e))
+    }
+
+    /// Status of the file at `path`, whose key is `key`, the content is only hashed when the size or modification time changed
+    pub fn status(&self, key: &str, path: &str) -> io::Result<FileStatus> {
+        let known = match self.files.get(key) {
+            Some(t) => t,
+            None => return Ok(FileStatus::New),
+        };
+        let (size, mtime) = metadata(path)?;
+        if known.size == size && known.mtime == mtime {
+            return Ok(FileStatus::Unchanged);
+        }
+        let entry = file_entry(path)?;
+        if entry.hash == known.hash {
+            Ok(FileStatus::Touched(entry))
+        } else {
+            Ok(FileStatus::Changed)
+        }
+    }
+}
+
+/// Key of a file in the manifest: its path relative to the source directory
+pub fn file_key(path: &str, source: &str) -> String {
+    match Path::new(path).strip_prefix(source) {
+        Ok(t) => t.to_string_lossy().into_owned(),
+        Err(_) => path.to_string(),
+    }
+}
+
+/// Size, modification time and hash of the file
+pub fn file_entry(path: &str) -> io::Result<FileEntry> {
+    let (size, mtime) = metadata(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(FileEntry {
+        size,
+        mtime,
+        hash: format!("{:x}", hasher.finalize()),
+    })
+}
+
+// Size and modification time
+fn metadata(path: &str) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or(0);
+    Ok((metadata.len(), mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_entry, file_key, FileStatus, Manifest};
+    use std::{
+        env,
+        fs::{self, File},
+        process,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn set_mtime(path: &str, mtime: u64) {
+        File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)))
+            .unwrap();
+    }
+
+    #[test]
+    fn status_tells_which_files_must_be_parsed() {
+        let directory = env::temp_dir().join(format!("manifest-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("fights.xz");
+        let path = path.to_str().unwrap();
+        let source = directory.to_str().unwrap();
+        fs::write(path, "fights").unwrap();
+        set_mtime(path, 1_561_777_197);
+        let key = file_key(path, source);
+        assert_eq!(key, "fights.xz");
+        let mut manifest = Manifest::new();
+        assert!(matches!(manifest.status(&key, path), Ok(FileStatus::New)));
+        manifest.files.insert(key.clone(), file_entry(path).unwrap());
+        assert!(matches!(manifest.status(&key, path), Ok(FileStatus::Unchanged)));
+        // Copied again: same content, later modification time
+        set_mtime(path, 1_561_777_198);
+        match manifest.status(&key, path) {
+            Ok(FileStatus::Touched(entry)) => assert_eq!(entry.mtime, 1_561_777_198),
+            _ => panic!("not touched"),
+        }
+        fs::write(path, "other fights").unwrap();
+        assert!(matches!(manifest.status(&key, path), Ok(FileStatus::Changed)));
+        // Same size and modification time, the content is not read
+        fs::write(path, "FIGHTS").unwrap();
+        set_mtime(path, 1_561_777_197);
+        assert!(matches!(manifest.status(&key, path), Ok(FileStatus::Unchanged)));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
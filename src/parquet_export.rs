@@ -0,0 +1,323 @@
+//! Parquet datasets of the accepted encounters, partitioned by region and patch.
+
+extern crate parquet;
+use self::parquet::{
+    basic::{Compression, Type as PhysicalType},
+    data_type::{ByteArray, ByteArrayType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::{parser::parse_message_type, types::TypePtr},
+};
+use process::EncounterRecord;
+use std::{
+    collections::HashMap,
+    fs,
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Rows buffered per partition and dataset before being written as a row group, checked once the rows of a whole file are added
+const ROW_GROUP_SIZE: usize = 65536;
+
+const ENCOUNTERS: &str = "message encounters {
+    required binary encounter_id (UTF8);
+    required binary file (UTF8);
+    required int64 timestamp;
+    required int64 area_id;
+    required int64 boss_id;
+    required int64 fight_duration;
+    required int64 party_size;
+}";
+
+const MEMBERS: &str = "message members {
+    required binary member_id (UTF8);
+    required binary encounter_id (UTF8);
+    required int64 timestamp;
+    required int64 area_id;
+    required int64 boss_id;
+    required binary class (UTF8);
+    required binary server (UTF8);
+    required int64 dps;
+}";
+
+const BUFFS: &str = "message buffs {
+    required binary member_id (UTF8);
+    required binary buff_id (UTF8);
+    required int64 uptime;
+}";
+
+enum Column {
+    Int64(Vec<i64>),
+    Utf8(Vec<ByteArray>),
+}
+
+impl Column {
+    fn push_int(&mut self, value: i64) {
+        if let Column::Int64(ref mut values) = *self {
+            values.push(value);
+        }
+    }
+
+    fn push_str(&mut self, value: &str) {
+        if let Column::Utf8(ref mut values) = *self {
+            values.push(ByteArray::from(value));
+        }
+    }
+}
+
+// One parquet file of a partition, written row group by row group
+struct Dataset {
+    schema: TypePtr,
+    path: String,
+    writer: Option<SerializedFileWriter<File>>,
+    columns: Vec<Column>,
+    rows: usize,
+}
+
+impl Dataset {
+    fn new(schema: &str, path: String) -> Dataset {
+        let schema = Arc::new(parse_message_type(schema).expect("Invalid parquet schema"));
+        let columns = schema
+            .get_fields()
+            .iter()
+            .map(|field| match field.get_physical_type() {
+                PhysicalType::INT64 => Column::Int64(Vec::new()),
+                _ => Column::Utf8(Vec::new()),
+            })
+            .collect();
+        Dataset {
+            schema,
+            path,
+            writer: None,
+            columns,
+            rows: 0,
+        }
+    }
+
+    fn row_added(&mut self) {
+        self.rows += 1;
+    }
+
+    fn flush_if_full(&mut self) -> Result<(), String> {
+        if self.rows >= ROW_GROUP_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let path = self.path.clone();
+        let error = |e: parquet::errors::ParquetError| format!("Unable to write {}: {}", path, e);
+        if self.writer.is_none() {
+            if let Some(parent) = Path::new(&self.path).parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Unable to create {}: {}", parent.display(), e))?;
+            }
+            let file =
+                File::create(&self.path).map_err(|e| format!("Unable to create {}: {}", self.path, e))?;
+            let properties = Arc::new(
+                WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build(),
+            );
+            self.writer = Some(
+                SerializedFileWriter::new(file, self.schema.clone(), properties).map_err(error)?,
+            );
+        }
+        let writer = self.writer.as_mut().unwrap();
+        let mut row_group = writer.next_row_group().map_err(error)?;
+        for column in &mut self.columns {
+            let mut column_writer = match row_group.next_column().map_err(error)? {
+                Some(t) => t,
+                None => break,
+            };
+            match *column {
+                Column::Int64(ref mut values) => {
+                    column_writer
+                        .typed::<Int64Type>()
+                        .write_batch(values, None, None)
+                        .map_err(error)?;
+                    values.clear();
+                }
+                Column::Utf8(ref mut values) => {
+                    column_writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(values, None, None)
+                        .map_err(error)?;
+                    values.clear();
+                }
+            }
+            column_writer.close().map_err(error)?;
+        }
+        row_group.close().map_err(error)?;
+        self.rows = 0;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        self.flush()?;
+        if let Some(writer) = self.writer.take() {
+            writer
+                .close()
+                .map_err(|e| format!("Unable to write {}: {}", self.path, e))?;
+        }
+        Ok(())
+    }
+}
+
+struct Partition {
+    encounters: Dataset,
+    members: Dataset,
+    buffs: Dataset,
+}
+
+impl Partition {
+    fn flush_if_full(&mut self) -> Result<(), String> {
+        self.encounters.flush_if_full()?;
+        self.members.flush_if_full()?;
+        self.buffs.flush_if_full()
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        self.encounters.close()?;
+        self.members.close()?;
+        self.buffs.close()
+    }
+}
+
+struct Partitions {
+    partitions: HashMap<(String, String), Partition>,
+    // First write failure, the files are incomplete from then on
+    error: Option<String>,
+}
+
+/// Accepted encounters, members and buffs as parquet datasets partitioned by region and patch:
+/// `<directory>/<dataset>/region=<region>/patch=<patch>/part-<run>.parquet`
+pub struct ParquetExport {
+    directory: String,
+    // Every run writes its own files, so that incremental runs only add files
+    part: String,
+    partitions: Mutex<Partitions>,
+}
+
+impl ParquetExport {
+    /// Keep the files of the previous runs when `append` is set, remove them otherwise
+    pub fn new(directory: &str, append: bool) -> Result<ParquetExport, String> {
+        if !append {
+            for dataset in &["encounters", "members", "buffs"] {
+                let path = Path::new(directory).join(dataset);
+                if path.exists() {
+                    fs::remove_dir_all(&path)
+                        .map_err(|e| format!("Unable to remove {}: {}", path.display(), e))?;
+                }
+            }
+        }
+        let run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        Ok(ParquetExport {
+            directory: directory.to_string(),
+            part: format!("part-{}.parquet", run),
+            partitions: Mutex::new(Partitions {
+                partitions: HashMap::new(),
+                error: None,
+            }),
+        })
+    }
+
+    /// Encounters of a whole file are added at once, so that an invalid file adds nothing,
+    /// and row groups are only written between files.
+    /// Encounter ids are `<file>#<index of the encounter in the file>`, member ids `<encounter id>#<index>`.
+    /// A write failure is returned by `close`, nothing is written after it.
+    pub fn write(&self, file: &str, encounters: &[EncounterRecord]) {
+        let mut partitions = self.partitions.lock().unwrap();
+        if partitions.error.is_some() {
+            return;
+        }
+        for (index, encounter) in encounters.iter().enumerate() {
+            let partition = partitions
+                .partitions
+                .entry((encounter.region.clone(), encounter.patch.clone()))
+                .or_insert_with(|| self.partition(&encounter.region, &encounter.patch));
+            let encounter_id = format!("{}#{}", file, index);
+            let columns = &mut partition.encounters.columns;
+            columns[0].push_str(&encounter_id);
+            columns[1].push_str(file);
+            columns[2].push_int(encounter.timestamp as i64);
+            columns[3].push_int(i64::from(encounter.area_id));
+            columns[4].push_int(i64::from(encounter.boss_id));
+            columns[5].push_int(encounter.fight_duration as i64);
+            columns[6].push_int(encounter.party_size as i64);
+            partition.encounters.row_added();
+            for (member_index, member) in encounter.members.iter().enumerate() {
+                let member_id = format!("{}#{}", encounter_id, member_index);
+                let columns = &mut partition.members.columns;
+                columns[0].push_str(&member_id);
+                columns[1].push_str(&encounter_id);
+                columns[2].push_int(encounter.timestamp as i64);
+                columns[3].push_int(i64::from(encounter.area_id));
+                columns[4].push_int(i64::from(encounter.boss_id));
+                columns[5].push_str(&member.class);
+                columns[6].push_str(&member.server);
+                columns[7].push_int(i64::from(member.dps));
+                partition.members.row_added();
+                for (buff_id, uptime) in &member.buffs {
+                    let columns = &mut partition.buffs.columns;
+                    columns[0].push_str(&member_id);
+                    columns[1].push_str(buff_id);
+                    columns[2].push_int(*uptime);
+                    partition.buffs.row_added();
+                }
+            }
+        }
+        let result = partitions
+            .partitions
+            .values_mut()
+            .try_for_each(Partition::flush_if_full);
+        partitions.error = result.err();
+    }
+
+    /// Write the remaining rows and the footers, files are not readable before.
+    /// Fails when a write failed, the incomplete files of the run are then removed
+    pub fn close(self) -> Result<(), String> {
+        let mut partitions = self.partitions.into_inner().unwrap();
+        if partitions.error.is_none() {
+            partitions.error = partitions
+                .partitions
+                .values_mut()
+                .try_for_each(Partition::close)
+                .err();
+        }
+        if let Some(error) = partitions.error {
+            for partition in partitions.partitions.values() {
+                for dataset in &[&partition.encounters, &partition.members, &partition.buffs] {
+                    if Path::new(&dataset.path).exists() {
+                        fs::remove_file(&dataset.path)
+                            .map_err(|e| format!("{}, unable to remove {}: {}", error, dataset.path, e))?;
+                    }
+                }
+            }
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    fn partition(&self, region: &str, patch: &str) -> Partition {
+        let path = |dataset: &str| {
+            format!(
+                "{}/{}/region={}/patch={}/{}",
+                self.directory, dataset, region, patch, self.part
+            )
+        };
+        Partition {
+            encounters: Dataset::new(ENCOUNTERS, path("encounters")),
+            members: Dataset::new(MEMBERS, path("members")),
+            buffs: Dataset::new(BUFFS, path("buffs")),
+        }
+    }
+}
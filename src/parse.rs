@@ -1,99 +1,151 @@
+//! Streaming parser of the input files, fights are filtered by the rules as they are read.
+
 extern crate serde;
 extern crate serde_json;
 extern crate xz2;
 use self::serde::{de, Deserializer};
 use self::xz2::read;
-use std::{fmt, fs::File, io::prelude::*, marker::PhantomData};
-impl StatsLog {
-    pub fn new(filename: &String) -> Result<Vec<StatsLog>, String> {
-        let mut decompressed = Vec::new();
-        {
-            let mut compressed = Vec::new();
-            File::open(filename)
-                .unwrap()
-                .read_to_end(&mut compressed)
-                .map_err(|_| format!("Unable to open {}", filename))?;
-            read::XzDecoder::new(&*compressed)
-                .read_to_end(&mut decompressed)
-                .map_err(|_| format!("Unable to decompress {}", filename))?;
-        }
-        let mut result: Vec<StatsLog> = serde_json::from_str(&String::from_utf8(decompressed).map_err(|_| format!("UTF8 invalid {}", filename))?)
-            .map_err(|e| format!("Unable to parse {}: {}", filename, e))?;
-        result.retain(| ref one_fight| !contain_forbidden_buff(one_fight) && !contain_forbidden_server(one_fight));
-        //result.retain(| ref one_fight| !contain_shit(one_fight, filename));
-        Ok(result)
-    }
-}
-
-fn contain_forbidden_buff(stat: &&StatsLog) -> bool{
-    let illegal_buff = vec!["25", "26", "27", "28", "37", "31", "36", "33"];
-    for member in &stat.content.members{
-        for buff in &member.buff_uptime{
-            if illegal_buff.contains(&&*(buff.key)) {
-                //println!("Illegal buff found: {}", buff.key);
-                return true;
-            }
-            if buff.key == "8888889" && buff.value.parse::<i16>().unwrap() > 50{
-                //println!("Slaying found");
-                return true;
-            }
-        }
+use error::{ErrorKind, ParseError, NUMERIC_COERCION};
+use rules::{find_rejecting_rule, Rule};
+use std::{
+    convert::TryFrom,
+    fmt,
+    fs::File,
+    io,
+    io::{prelude::*, BufReader},
+    marker::PhantomData,
+};
 
-    }
-    //println!("Yukikoo");
-    false
+/// One fight read from a file, accepted or rejected by the rules
+pub enum Parsed {
+    /// Kept for the statistics
+    Accepted(StatsLog),
+    /// Matched a rule, only counted in the data quality report
+    Rejected(RejectedLog),
+}
+
+/// What is left of a rejected fight, enough to count it
+pub struct RejectedLog {
+    /// Region of the upload
+    pub region: String,
+    /// Unix timestamp of the fight
+    pub timestamp: u64,
+    /// Area of the fight
+    pub area_id: u32,
+    /// Boss of the fight
+    pub boss_id: u32,
+    /// Members of the party
+    pub members: usize,
+    /// Name of the rejecting rule, see `Rule::reason`
+    pub reason: &'static str,
 }
 
-fn contain_forbidden_server(stat: &&StatsLog) -> bool{
-    let directory_vec: Vec<&str> = stat.directory.split(".").collect();
-    let region = directory_vec[0];
-    if region == "EU" {
-        for member in &stat.content.members{
-            if member.player_server != "Killian" &&
-                    member.player_server != "Seren" &&
-                    member.player_server != "Mystel" &&
-                    member.player_server != "Yurian"{
-                        //println!("rejected server: {}", member.player_server);
-                        return true;
-                    }
+/// Decompress and parse a file fight by fight, without loading it entirely in memory.
+/// The content is either a json array of fights or newline delimited json (one fight per line).
+/// Fights read before an error are given to `on_fight`.
+pub fn read_file<F>(filename: &str, rules: &[Rule], mut on_fight: F) -> Result<(), ParseError>
+where
+    F: FnMut(Parsed),
+{
+    let file = File::open(filename).map_err(|e| ParseError::new(filename, ErrorKind::Io(e)))?;
+    let mut reader = BufReader::new(read::XzDecoder::new(BufReader::new(file)));
+    let mut handle = |one_fight: StatsLog| {
+        on_fight(match find_rejecting_rule(rules, &one_fight) {
+            Some(rule) => Parsed::Rejected(RejectedLog {
+                region: one_fight.region().to_string(),
+                timestamp: one_fight.content.timestamp,
+                area_id: one_fight.content.area_id,
+                boss_id: one_fight.content.boss_id,
+                members: one_fight.content.members.len(),
+                reason: rule.reason(),
+            }),
+            None => Parsed::Accepted(one_fight),
+        })
+    };
+    let first_byte =
+        first_byte(&mut reader).map_err(|e| ParseError::new(filename, ErrorKind::Decompress(e)))?;
+    if first_byte == Some(b'[') {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        deserializer
+            .deserialize_seq(FightsVisitor(&mut handle))
+            .and_then(|_| deserializer.end())
+            .map_err(|e| ParseError::from_json(filename, e))
+    } else {
+        for one_fight in serde_json::Deserializer::from_reader(reader).into_iter::<StatsLog>() {
+            handle(one_fight.map_err(|e| ParseError::from_json(filename, e))?);
         }
-        return false;
+        Ok(())
+    }
+}
 
+// Skip the leading whitespaces and peek the first meaningful byte
+fn first_byte<R: BufRead>(reader: &mut R) -> io::Result<Option<u8>> {
+    loop {
+        let (found, skipped) = {
+            let buffer = reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            match buffer.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(position) => (Some(buffer[position]), position),
+                None => (None, buffer.len()),
+            }
+        };
+        reader.consume(skipped);
+        if found.is_some() {
+            return Ok(found);
+        }
     }
+}
 
-    false
+struct FightsVisitor<'a, F: 'a>(&'a mut F);
 
-}
+impl<'de, 'a, F> de::Visitor<'de> for FightsVisitor<'a, F>
+where
+    F: FnMut(StatsLog),
+{
+    type Value = ();
 
-fn contain_shit(stat: &&StatsLog, filename: &str)->bool{
-    if stat.content.area_id != 444{
-        return false;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of fights")
     }
-    for member in &stat.content.members{
-        if member.player_class == "Ninja" && member.player_dps.parse::<u64>().unwrap() > 9_000_000{
-            println!("filename: {}", filename);
-            println!("party size: {}", &stat.content.members.len());
-            println!("trouve {}: {:?}", member.player_dps, member.buff_uptime);
-            return false;
-       }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        while let Some(one_fight) = seq.next_element::<StatsLog>()? {
+            (self.0)(one_fight);
+        }
+        Ok(())
     }
-    return false;
+}
 
+impl StatsLog {
+    /// Region of the upload, the part of `directory` before the first dot
+    pub fn region(&self) -> &str {
+        self.directory.split('.').next().unwrap_or("")
+    }
 }
 
-// Full json structure
+/// Full json structure
 #[derive(Deserialize)]
 pub struct StatsLog {
+    /// The fight
     pub content: Encounter,
+    /// `<region>.<...>`, where the meter uploaded the fight
     pub directory: String,
     //name: String,
 }
 
 
+/// Uptime of one buff of a member
 #[derive(Deserialize,Debug)]
 pub struct BuffUptime{
+    /// Buff id
     #[serde(rename="Key")]
     pub key: String,
+    /// Uptime in %, as a string
     #[serde(rename="Value")]
     pub value: String,
 }
@@ -114,19 +166,21 @@ where
         where
             E: de::Error,
         {
-            Ok(value.parse::<u32>().unwrap())
+            value
+                .parse::<u32>()
+                .map_err(|_| E::custom(format!("{}{}", NUMERIC_COERCION, value)))
         }
         fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            Ok(value as u32)
+            u32::try_from(value).map_err(|_| E::custom(format!("{}{}", NUMERIC_COERCION, value)))
         }
         fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            Ok(value as u32)
+            u32::try_from(value).map_err(|_| E::custom(format!("{}{}", NUMERIC_COERCION, value)))
         }
     }
 
@@ -148,28 +202,95 @@ where
         where
             E: de::Error,
         {
-            Ok(value.parse::<u64>().unwrap())
+            value
+                .parse::<u64>()
+                .map_err(|_| E::custom(format!("{}{}", NUMERIC_COERCION, value)))
         }
         fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            Ok(value as u64)
+            Ok(value)
         }
         fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            Ok(value as u64)
+            u64::try_from(value).map_err(|_| E::custom(format!("{}{}", NUMERIC_COERCION, value)))
         }
     }
 
     deserializer.deserialize_any(StringOrInt(PhantomData))
 }
+// Skill values are strings for most meters, numbers for some, null as if missing
+fn string_from_str_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct StringOrNumber;
+    impl<'de> de::Visitor<'de> for StringOrNumber {
+        type Value = String;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("string, number or null")
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(String::new())
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(value.to_string())
+        }
+        fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(value.to_string())
+        }
+        fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(value.to_string())
+        }
+        fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(value.to_string())
+        }
+    }
+
+    deserializer.deserialize_any(StringOrNumber)
+}
+
+// A null skill log is empty, skills without an id are left out
+fn skill_log_entries<'de, D>(deserializer: D) -> Result<Vec<SkillLog>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries: Option<Vec<SkillLog>> = de::Deserialize::deserialize(deserializer)?;
+    Ok(entries
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|skill| !skill.skill_id.is_empty())
+        .collect())
+}
+
+/// One fight, as sent by the meter
 #[derive(Deserialize)]
 pub struct Encounter {
+    /// Area of the fight
     #[serde(rename = "areaId", deserialize_with = "u32_from_str_or_int")]
     pub area_id: u32,
+    /// Boss of the fight
     #[serde(rename = "bossId", deserialize_with = "u32_from_str_or_int")]
     pub boss_id: u32,
     //#[serde(rename="debuffDetail")]
@@ -178,9 +299,12 @@ pub struct Encounter {
     //debuff_uptime: Vec<Value>,
     //#[serde(rename="encounterUnixEpoch")]
     //encounter_unix_epoch: i64,
+    /// Seconds
     #[serde(rename = "fightDuration", deserialize_with = "u64_from_str_or_int")]
     pub fight_duration: u64,
+    /// Unix timestamp of the fight
     pub timestamp: u64,
+    /// Members of the party
     pub members: Vec<Members>,
     //#[serde(rename="meterName")]
     //meter_name: String,
@@ -192,13 +316,15 @@ pub struct Encounter {
     //uploader: String, //zero-based index of uploader in members list
 }
 
+/// One member of the party
 #[derive(Deserialize)]
 pub struct Members {
     //aggro: String,
     //#[serde(rename="buffDetail")]
     //buff_detail: Vec<Value>,
+    /// Uptime of the buffs of the member
     #[serde(rename="buffUptime")]
-    buff_uptime: Vec<BuffUptime>,
+    pub buff_uptime: Vec<BuffUptime>,
     //#[serde(default)]
     //guild: String,
     //#[serde(default)]
@@ -206,48 +332,152 @@ pub struct Members {
     //heal_crit: String,
     //#[serde(rename="playerAverageCritRate")]
     //player_average_crit_rate: String,
+    /// Class as sent by the meter, see `config::ClassRegistry`
     #[serde(rename = "playerClass")]
     pub player_class: String,
     //#[serde(rename="playerDeathDuration")]
     //player_death_duration: String,
     //#[serde(rename="playerDeaths")]
     //player_deaths: String,
+    /// Dps, as a string
     #[serde(rename = "playerDps")]
     pub player_dps: String,
     //#[serde(rename="playerId")]
     //player_id:u32,
-    //#[serde(rename = "playerName")]
-    //pub player_name: String,
+    /// Character name, empty when not sent
+    #[serde(rename = "playerName", default)]
+    pub player_name: String,
+    /// Server of the character
     #[serde(rename = "playerServer")]
     pub player_server: String,
     //#[serde(rename="playerTotalDamage")]
     //player_total_damage: String,
     //#[serde(rename="playerTotalDamagePercentage")]
     //player_total_damage_percentage: String,
-    //#[serde(rename="skillLog")]
-    //skill_log: Vec<SkillLog>,
+    /// Skills without an id are left out
+    #[serde(rename = "skillLog", default, deserialize_with = "skill_log_entries")]
+    pub skill_log: Vec<SkillLog>,
     //#[serde(rename="skillCasts")]
     //skill_casts: Vec<Vec<i32>>,
 }
 
-#[derive(Deserialize)]
+/// Damage of one skill of a member, values are kept as sent by the meter and parsed when used, missing ones are empty
+#[derive(Deserialize, Debug)]
 pub struct SkillLog {
     //#[serde(rename="skillAverageCrit")]
-//skill_average_crit: String,
-//#[serde(rename="skillAverageWhite")]
-//skill_average_white: String,
-//#[serde(rename="skillCritRate")]
-//skill_crit_rate: String,
-//#[serde(rename="skillDamagePercent")]
-//skill_damage_percent: String,
-//#[serde(rename="skillHighestCrit")]
-//skill_highest_crit: String,
-//#[serde(rename="skillHits")]
-//skill_hits: String,
-//#[serde(rename="skillId")]
-//skill_id: String,
-//#[serde(rename="skillLowestCrit")]
-//skill_lowest_crit: String,
-//#[serde(rename="skillTotalDamage")]
-//skill_total_damage: String,
+    //skill_average_crit: String,
+    //#[serde(rename="skillAverageWhite")]
+    //skill_average_white: String,
+    /// Crit rate in %, may be decimal
+    #[serde(rename = "skillCritRate", default, deserialize_with = "string_from_str_or_number")]
+    pub skill_crit_rate: String,
+    /// Share of the skill in the damage of the member in %, as computed by the meter
+    #[serde(rename = "skillDamagePercent", default, deserialize_with = "string_from_str_or_number")]
+    pub skill_damage_percent: String,
+    /// Damage of the highest crit
+    #[serde(rename = "skillHighestCrit", default, deserialize_with = "string_from_str_or_number")]
+    pub skill_highest_crit: String,
+    /// Hits of the skill
+    #[serde(rename = "skillHits", default, deserialize_with = "string_from_str_or_number")]
+    pub skill_hits: String,
+    /// Skill id
+    #[serde(rename = "skillId", default, deserialize_with = "string_from_str_or_number")]
+    pub skill_id: String,
+    /// Damage of the lowest crit, 0 when the skill never crit
+    #[serde(rename = "skillLowestCrit", default, deserialize_with = "string_from_str_or_number")]
+    pub skill_lowest_crit: String,
+    /// Damage of the skill
+    #[serde(rename = "skillTotalDamage", default, deserialize_with = "string_from_str_or_number")]
+    pub skill_total_damage: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::xz2::write::XzEncoder;
+    use super::{read_file, Parsed};
+    use error::{ErrorKind, ParseError};
+    use std::{env, fs, io::Write, process};
+
+    const FIGHT: &str = r#"{"content": {"areaId": "444", "bossId": 2000, "fightDuration": "455", "timestamp": 1561777197,
+        "members": [{"buffUptime": [{"Key": "4030", "Value": "63"}], "playerClass": "Warrior", "playerDps": "3684443",
+        "playerServer": "Killian"}]}, "directory": "EU.1"}"#;
+
+    fn xz(content: &[u8]) -> Vec<u8> {
+        let mut encoder = XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Fights of a file holding `bytes`, as `<area>-<boss>:<duration>:<dps of the members>`
+    fn parse(name: &str, bytes: &[u8]) -> (Vec<String>, Result<(), ParseError>) {
+        let filename = env::temp_dir().join(format!("{}-{}.xz", name, process::id()));
+        fs::write(&filename, bytes).unwrap();
+        let mut fights = Vec::new();
+        let result = read_file(filename.to_str().unwrap(), &[], |parsed| {
+            if let Parsed::Accepted(fight) = parsed {
+                let dps: Vec<&str> = fight.content.members.iter().map(|member| &*member.player_dps).collect();
+                fights.push(format!(
+                    "{}-{}:{}:{}",
+                    fight.content.area_id,
+                    fight.content.boss_id,
+                    fight.content.fight_duration,
+                    dps.join(",")
+                ));
+            }
+        });
+        fs::remove_file(&filename).unwrap();
+        (fights, result)
+    }
+
+    fn kind(name: &str, bytes: &[u8]) -> ErrorKind {
+        parse(name, bytes).1.unwrap_err().kind
+    }
+
+    #[test]
+    fn array_and_ndjson_files_give_the_same_fights() {
+        let second = FIGHT.replace(r#""bossId": 2000"#, r#""bossId": "1000""#).replace("3684443", "12");
+        let array = format!(" \n[{},\n{}]\n", FIGHT, second);
+        let ndjson = format!("{}\n\n{}\n", FIGHT.replace('\n', ""), second.replace('\n', ""));
+        let (array_fights, array_result) = parse("array", &xz(array.as_bytes()));
+        let (ndjson_fights, ndjson_result) = parse("ndjson", &xz(ndjson.as_bytes()));
+        assert!(array_result.is_ok() && ndjson_result.is_ok());
+        assert_eq!(array_fights, vec!["444-2000:455:3684443", "444-1000:455:12"]);
+        assert_eq!(ndjson_fights, array_fights);
+    }
+
+    #[test]
+    fn malformed_number_is_a_numeric_coercion() {
+        let content = FIGHT.replace(r#""fightDuration": "455""#, r#""fightDuration": "45x""#);
+        match kind("numeric", &xz(content.as_bytes())) {
+            ErrorKind::NumericCoercion { line, ref value, .. } => assert_eq!((line, &**value), (1, "45x")),
+            ref other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn broken_json_is_a_syntax_error() {
+        let content = format!("[{}", &FIGHT[..FIGHT.len() - 10]);
+        match kind("syntax", &xz(content.as_bytes())) {
+            ErrorKind::JsonSyntax { .. } => (),
+            ref other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn file_which_is_not_xz_is_a_decompression_error() {
+        match kind("decompress", FIGHT.as_bytes()) {
+            ErrorKind::Decompress(_) => (),
+            ref other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_as_such() {
+        let mut content = FIGHT.as_bytes().to_vec();
+        content[FIGHT.find("Warrior").unwrap() + 3] = 0xff;
+        match kind("utf8", &xz(&content)) {
+            ErrorKind::Utf8 { line, .. } => assert_eq!(line, 2),
+            ref other => panic!("{:?}", other),
+        }
+    }
 }
@@ -1,216 +1,757 @@
-use bidir_map::BidirMap;
-use parse::StatsLog;
-use std::collections::HashMap;
-use chrono::{TimeZone, prelude::*};
-#[derive(PartialEq, Hash, Eq, Clone)]
-pub enum Class {
-    Archer,
-    Berserker,
-    Brawler,
-    Gunner,
-    Lancer,
-    Mystic,
-    Ninja,
-    Priest,
-    Reaper,
-    Slayer,
-    Sorcerer,
-    Valkyrie,
-    Warrior,
-}
+//! Aggregates of the accepted fights, and the statistics computed from them.
+
+extern crate serde;
+use self::serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use config::{ClassRegistry, Config, RegionMap, Role, Statistics};
+use distribution::{self, Backend, Distribution, KeyQuantiles, QuantileMethod, Summary};
+use parse::{Members, RejectedLog, SkillLog, StatsLog};
+use quality::{QualityReport, NO_PATCH};
+use segment::{self, SegmentMap};
+use trend::{self, TrendMap};
+use usage::{self, UsageData};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+/// Canonical class name, as declared in the class registry
+pub type Class = String;
+
+// Class compositions of larger parties (raids) hardly repeat, one key per clear would be kept
+const CLASS_COMPOSITION_MAX_PARTY: usize = 5;
 
+/// Aggregates of the members of one class
+#[derive(Serialize, Deserialize)]
 pub struct DataDetails {
-    pub dps: Vec<u32>,
+    /// Dps of every member
+    pub dps: Distribution,
+    /// Lower bound of the dps bucket -> members
     pub stepped_dps: HashMap<u32, u32>,
+    /// Buff id -> uptime (%) -> members, members without the buff are not counted
+    pub buffs: HashMap<String, HashMap<u32, u64>>,
+    /// Members with a skill log, 0 unless `[skills]` is enabled
+    pub skill_members: u64,
+    /// Skill id -> damage, empty unless `[skills]` is enabled
+    pub skills: HashMap<String, SkillDetails>,
 }
 
 impl DataDetails {
-    fn new() -> DataDetails {
+    fn new(backend: Backend) -> DataDetails {
         DataDetails {
-            dps: Vec::new(),
+            dps: Distribution::new(backend),
             stepped_dps: HashMap::new(),
+            buffs: HashMap::new(),
+            skill_members: 0,
+            skills: HashMap::new(),
         }
     }
 
-    fn add(&mut self, new_dps: u32, new_stepped: u32) {
-        self.dps.push(new_dps);
+    fn add(&mut self, new_dps: u32, new_stepped: u32, member: &Members, skills: bool) {
+        self.dps.add(u64::from(new_dps));
         *(self.stepped_dps.entry(new_stepped).or_insert(0)) += 1;
+        for buff in &member.buff_uptime {
+            if let Ok(uptime) = buff.value.parse() {
+                *(self
+                    .buffs
+                    .entry(buff.key.clone())
+                    .or_default()
+                    .entry(uptime)
+                    .or_insert(0)) += 1;
+            }
+        }
+        if !skills {
+            return;
+        }
+        // Skills with an unreadable damage are left out, as if the member did not use them
+        let skills: Vec<(&SkillLog, u64)> = member
+            .skill_log
+            .iter()
+            .filter_map(|skill| Some((skill, skill.skill_total_damage.parse().ok()?)))
+            .collect();
+        let total_damage: u64 = skills.iter().map(|&(_, damage)| damage).sum();
+        if total_damage == 0 {
+            return;
+        }
+        self.skill_members += 1;
+        for (skill, damage) in skills {
+            self.skills
+                .entry(skill.skill_id.clone())
+                .or_default()
+                .add(skill, damage, total_damage);
+        }
+    }
+
+    fn merge(&mut self, other: DataDetails) {
+        self.dps.merge(other.dps);
+        for (stepped, count) in other.stepped_dps {
+            *(self.stepped_dps.entry(stepped).or_insert(0)) += count;
+        }
+        for (buff, uptimes) in other.buffs {
+            let buff_uptimes = self.buffs.entry(buff).or_default();
+            for (uptime, count) in uptimes {
+                *(buff_uptimes.entry(uptime).or_insert(0)) += count;
+            }
+        }
+        self.skill_members += other.skill_members;
+        for (skill, details) in other.skills {
+            match self.skills.entry(skill) {
+                Entry::Occupied(mut t) => t.get_mut().merge(details),
+                Entry::Vacant(t) => {
+                    t.insert(details);
+                }
+            }
+        }
+    }
+}
+
+/// Damage of one skill among the members of a class using it
+#[derive(Serialize, Deserialize, Default)]
+pub struct SkillDetails {
+    /// Share of the damage of a member using the skill, in per mille of the damage of all its skills -> members
+    pub share: HashMap<u32, u64>,
+    /// Damage of the skill, summed over the members
+    pub damage: u64,
+    /// Sum of the damage percents sent by the meter, over the members with a readable one
+    pub damage_percent: f64,
+    /// Members with a readable damage percent
+    pub damage_percent_members: u64,
+    /// Hits of the skill, summed over the members
+    pub hits: u64,
+    /// Sum of the crit rates (%) times the hits, for the crit rate weighted by hits
+    pub crit_hits: f64,
+    /// Highest crit seen
+    pub highest_crit: u64,
+    /// 0 when the skill never crit
+    pub lowest_crit: u64,
+}
+
+impl SkillDetails {
+    fn add(&mut self, skill: &SkillLog, damage: u64, total_damage: u64) {
+        let hits: u64 = skill.skill_hits.parse().unwrap_or(0);
+        let crit_rate = parse_percent(&skill.skill_crit_rate).unwrap_or(0.0);
+        *(self.share.entry((damage * 1000 / total_damage) as u32).or_insert(0)) += 1;
+        self.damage += damage;
+        if let Some(percent) = parse_percent(&skill.skill_damage_percent) {
+            self.damage_percent += percent;
+            self.damage_percent_members += 1;
+        }
+        self.hits += hits;
+        self.crit_hits += crit_rate * hits as f64;
+        self.highest_crit = self
+            .highest_crit
+            .max(skill.skill_highest_crit.parse().unwrap_or(0));
+        self.add_lowest_crit(skill.skill_lowest_crit.parse().unwrap_or(0));
+    }
+
+    fn add_lowest_crit(&mut self, lowest_crit: u64) {
+        if lowest_crit > 0 && (self.lowest_crit == 0 || lowest_crit < self.lowest_crit) {
+            self.lowest_crit = lowest_crit;
+        }
+    }
+
+    fn merge(&mut self, other: SkillDetails) {
+        for (share, count) in other.share {
+            *(self.share.entry(share).or_insert(0)) += count;
+        }
+        self.damage += other.damage;
+        self.damage_percent += other.damage_percent;
+        self.damage_percent_members += other.damage_percent_members;
+        self.hits += other.hits;
+        self.crit_hits += other.crit_hits;
+        self.highest_crit = self.highest_crit.max(other.highest_crit);
+        self.add_lowest_crit(other.lowest_crit);
     }
 }
 
+// Percents may be decimal, "45.5"
+fn parse_percent(value: &str) -> Option<f64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| percent.is_finite() && *percent >= 0.0)
+}
+
+/// Aggregates of one fight, region and patch
+#[derive(Serialize, Deserialize)]
 pub struct DungeonData {
+    /// Canonical class -> its members
     pub members: HashMap<Class, DataDetails>,
-    //pub healers_number: HashMap<u8, u32>,
-    pub clear_time: Vec<u64>,
+    /// Fight duration of every encounter, in seconds
+    pub clear_time: Distribution,
+    /// Clear times per party composition, see `compositions`
+    pub role_compositions: HashMap<String, Distribution>,
+    /// Clear times per class composition, see `compositions`, only for parties of up to 5 members
+    pub class_compositions: HashMap<String, Distribution>,
 }
 
 impl DungeonData {
-    fn new() -> DungeonData {
+    fn new(backend: Backend) -> DungeonData {
         DungeonData {
             members: HashMap::new(),
-            //healers_number: HashMap::new(),
-            clear_time: Vec::new(),
+            clear_time: Distribution::new(backend),
+            role_compositions: HashMap::new(),
+            class_compositions: HashMap::new(),
+        }
+    }
+
+    fn merge(&mut self, other: DungeonData) {
+        for (class, details) in other.members {
+            match self.members.entry(class) {
+                Entry::Occupied(mut t) => t.get_mut().merge(details),
+                Entry::Vacant(t) => {
+                    t.insert(details);
+                }
+            }
+        }
+        self.clear_time.merge(other.clear_time);
+        merge_compositions(&mut self.role_compositions, other.role_compositions);
+        merge_compositions(&mut self.class_compositions, other.class_compositions);
+    }
+}
+
+fn merge_compositions(
+    compositions: &mut HashMap<String, Distribution>,
+    other: HashMap<String, Distribution>,
+) {
+    for (composition, clear_time) in other {
+        match compositions.entry(composition) {
+            Entry::Occupied(mut t) => t.get_mut().merge(clear_time),
+            Entry::Vacant(t) => {
+                t.insert(clear_time);
+            }
         }
     }
 }
 
+/// `<region>-<patch>` key, see `get_key` -> aggregates
 pub type Data = HashMap<String, DungeonData>;
+/// Every aggregate of a run, see `state::State` for their snapshot
+#[derive(Serialize, Deserialize, Default)]
 pub struct GlobalData {
+    /// Fight -> region and patch -> aggregates
     pub fights: HashMap<Fight, Data>,
-    //pub usage: HashMap<String, HashMap<String, u32>>,
+    /// Encounters and parties per day and hour of the week
+    pub usage: UsageData,
+    /// Dps and clear times per time bucket, empty without a `[trend]` section
+    pub trends: TrendMap,
+    /// Dps with and without the buffs of the `[[segments]]` sections
+    pub segments: SegmentMap,
 }
 
 impl GlobalData{
+    /// No data
     pub fn new() -> GlobalData{
         GlobalData{
             fights: HashMap::new(),
-      //      usage: HashMap::new(),
+            usage: UsageData::new(),
+            trends: TrendMap::new(),
+            segments: SegmentMap::new(),
+        }
+    }
+
+    /// Add the data of another run, as if both inputs were processed together
+    pub fn merge(&mut self, other: GlobalData) {
+        for (fight, other_data) in other.fights {
+            let data = self.fights.entry(fight).or_default();
+            for (key, dungeon_data) in other_data {
+                match data.entry(key) {
+                    Entry::Occupied(mut t) => t.get_mut().merge(dungeon_data),
+                    Entry::Vacant(t) => {
+                        t.insert(dungeon_data);
+                    }
+                }
+            }
         }
+        usage::merge(&mut self.usage, other.usage);
+        trend::merge(&mut self.trends, other.trends);
+        segment::merge(&mut self.segments, other.segments);
     }
 }
 
+/// Boss of an area, the unit of all the statistics
 #[derive(Eq, PartialEq, Hash)]
 pub struct Fight {
+    /// Area of the fight
     pub area_id: u32,
+    /// Boss of the fight
     pub boss_id: u32,
 }
 
 impl Fight {
-    fn new(area_id: u32, boss_id: u32) -> Fight {
+    /// Fight of a boss in an area
+    pub fn new(area_id: u32, boss_id: u32) -> Fight {
         Fight {
-            area_id: area_id,
-            boss_id: boss_id,
+            area_id,
+            boss_id,
         }
     }
+    /// `<area>-<boss>`, as used in the outputs
     pub fn to_str(&self) -> String {
         format!("{}-{}", self.area_id, self.boss_id)
     }
 }
 
+// Serialized as its "area-boss" name so it can be used as a map key
+impl Serialize for Fight {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Fight {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Fight, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        let mut ids = name.splitn(2, '-').map(|id| id.parse::<u32>());
+        match (ids.next(), ids.next()) {
+            (Some(Ok(area_id)), Some(Ok(boss_id))) => Ok(Fight::new(area_id, boss_id)),
+            _ => Err(de::Error::custom(format!("invalid fight {}", name))),
+        }
+    }
+}
+
+/// Key of a region and patch in `Data`
 pub fn get_key(region: &str, patch_name: &str) -> String {
     format!("{}-{}", region, patch_name)
 }
 
-fn get_patch_name(
-    region_map: &HashMap<&str, HashMap<&str, (u64, u64)>>,
-    region: &str,
-    timestamp: u64,
-) -> Option<String> {
-    let region_data = match region_map.get(region) {
-        Some(t) => t,
-        None => return None,
-    };
+/// Patch of the region whose window contains the timestamp, None outside every window
+pub fn get_patch_name(region_map: &RegionMap, region: &str, timestamp: u64) -> Option<String> {
+    let region_data = region_map.get(region)?;
     for (patch_name, patch_date) in region_data {
-        if patch_date.0 < timestamp && patch_date.1 > timestamp {
+        if patch_date.0 <= timestamp && timestamp < patch_date.1 {
             return Some(patch_name.to_string());
         }
     }
     None
 }
 
+/// Add an accepted fight to the aggregates, counting in `quality` the members which cannot be used.
+/// The skill logs are only aggregated when `[skills]` is enabled
 pub fn store(
-    contents: Vec<StatsLog>,
+    content: StatsLog,
     dps_steps: u32,
+    backend: Backend,
     data: &mut GlobalData,
-    class_map: &BidirMap<&str, Class>,
-    region_map: &HashMap<&str, HashMap<&str, (u64, u64)>>,
+    config: &Config,
+    quality: &mut QualityReport,
 ) {
-    for content in contents {
-        let directory_vec: Vec<&str> = content.directory.split(".").collect();
-        let region = directory_vec[0];
-        let timestamp = content.content.timestamp;
-        //let date = Utc.timestamp(timestamp as i64, 0).format("%Y-%m-%d").to_string();
-        //*(data.usage.entry(date).or_insert(HashMap::new()).entry(region.to_string()).or_insert(0)) += 1;
-        let patch_name = match get_patch_name(region_map, region, timestamp) {
-            Some(t) => t,
-            None => continue,
-        };
-        let fight = Fight::new(content.content.area_id, content.content.boss_id);
-        let key = get_key(region, &patch_name);
-        let dungeon_data = data.fights.entry(fight)
-            .or_insert(Data::new())
-            .entry(key)
-            .or_insert(DungeonData::new());
-        dungeon_data.clear_time.push(content.content.fight_duration);
-        //let mut healers_number: u8 = 0;
-        for member in content.content.members {
-            let class = match class_map.get_by_first(&&*(member.player_class)) {
-                Some(c) => c,
-                None => continue,
-            };
-            let mut dps: u32 = 0;
-            match member.player_dps.parse() {
-                Ok(value) => dps = value,
-                Err(_) => {}
-            };
-            let stepped_dps = ((dps / dps_steps) as u32) * dps_steps;
-            //if class == &Class::Mystic || class == &Class::Priest {
-            //    healers_number += 1;
-            //}
+    let class_registry = &config.classes;
+    let region = content.region();
+    let timestamp = content.content.timestamp;
+    let fight = Fight::new(content.content.area_id, content.content.boss_id);
+    let fight_name = fight.to_str();
+    let patch_name = match get_patch_name(&config.regions, region, timestamp) {
+        Some(t) => t,
+        None => {
+            quality.add_rejected_encounter(
+                region,
+                NO_PATCH,
+                &fight_name,
+                "no_patch_window",
+                content.content.members.len() as u64,
+            );
+            return;
+        }
+    };
+    let key = get_key(region, &patch_name);
+    let dungeon_data = data.fights.entry(fight)
+        .or_default()
+        .entry(key)
+        .or_insert_with(|| DungeonData::new(backend));
+    dungeon_data.clear_time.add(content.content.fight_duration);
+    if let Some((roles, classes)) = compositions(&content, class_registry) {
+        let fight_duration = content.content.fight_duration;
+        dungeon_data
+            .role_compositions
+            .entry(roles)
+            .or_insert_with(|| Distribution::new(backend))
+            .add(fight_duration);
+        if content.content.members.len() <= CLASS_COMPOSITION_MAX_PARTY {
             dungeon_data
-                .members
-                .entry(class.clone())
-                .or_insert(DataDetails::new())
-                .add(dps, stepped_dps);
+                .class_compositions
+                .entry(classes)
+                .or_insert_with(|| Distribution::new(backend))
+                .add(fight_duration);
+        }
+    }
+    let mut accepted_members = 0;
+    for member in &content.content.members {
+        let class = match class_registry.get(&member.player_class) {
+            Some(c) => c,
+            None => {
+                quality.add_unknown_class(&member.player_class);
+                quality.add_rejected_member(region, &patch_name, &fight_name, "unknown_class");
+                continue;
+            }
+        };
+        let dps: u32 = match member.player_dps.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                quality.add_rejected_member(region, &patch_name, &fight_name, "unparsable_dps");
+                continue;
+            }
+        };
+        accepted_members += 1;
+        let stepped_dps = (dps / dps_steps) * dps_steps;
+        dungeon_data
+            .members
+            .entry(class.clone())
+            .or_insert_with(|| DataDetails::new(backend))
+            .add(dps, stepped_dps, member, config.skills.enabled);
+    }
+    quality.add_accepted(region, &patch_name, &fight_name, accepted_members);
+}
+
+/// Party composition as role counts, `<tanks>-<healers>-<dps>`, and as classes sorted and joined by `+`.
+/// None when a member has an unknown class, as the composition would be incomplete.
+pub fn compositions(content: &StatsLog, class_registry: &ClassRegistry) -> Option<(String, String)> {
+    let mut role_counts = [0; 3];
+    let mut classes = Vec::new();
+    for member in &content.content.members {
+        let class = class_registry.get(&member.player_class)?;
+        match class_registry.role(class)? {
+            Role::Tank => role_counts[0] += 1,
+            Role::Healer => role_counts[1] += 1,
+            Role::Dps => role_counts[2] += 1,
         }
-        /*
-        *(dungeon_data
-            .healers_number
-            .entry(healers_number)
-            .or_insert(0)) += 1;
-        */
+        classes.push(class.as_str());
     }
+    classes.sort_unstable();
+    Some((
+        format!("{}-{}-{}", role_counts[0], role_counts[1], role_counts[2]),
+        classes.join("+"),
+    ))
+}
+
+/// Members kept in the statistics, with their canonical class and dps, for the exports of single encounters
+pub fn accepted_members<'a>(
+    content: &'a StatsLog,
+    class_registry: &'a ClassRegistry,
+) -> impl Iterator<Item = (&'a Members, &'a Class, u32)> + 'a {
+    content.content.members.iter().filter_map(move |member| {
+        let class = class_registry.get(&member.player_class)?;
+        let dps = member.player_dps.parse().ok()?;
+        Some((member, class, dps))
+    })
+}
+
+/// Accepted encounter with the members kept in the statistics
+pub struct EncounterRecord {
+    /// Unix timestamp of the fight
+    pub timestamp: u64,
+    /// Region
+    pub region: String,
+    /// Patch, named in the configuration
+    pub patch: String,
+    /// Area of the fight
+    pub area_id: u32,
+    /// Boss of the fight
+    pub boss_id: u32,
+    /// Seconds
+    pub fight_duration: u64,
+    /// Members of the party, the ones left out of the statistics included
+    pub party_size: usize,
+    /// Members kept in the statistics
+    pub members: Vec<MemberRecord>,
+}
+
+/// Member of an accepted encounter, with a known class and a readable dps
+pub struct MemberRecord {
+    /// Canonical class
+    pub class: String,
+    /// Server of the character
+    pub server: String,
+    /// Dps
+    pub dps: u32,
+    /// Buff id -> uptime (%), buffs with an unreadable uptime left out
+    pub buffs: Vec<(String, i64)>,
+}
+
+/// Single encounter, for the exports of the accepted encounters
+pub fn record(
+    log: &StatsLog,
+    class_registry: &ClassRegistry,
+    region_map: &RegionMap,
+) -> Option<EncounterRecord> {
+    let region = log.region();
+    let fight = &log.content;
+    let patch = get_patch_name(region_map, region, fight.timestamp)?;
+    let members = accepted_members(log, class_registry)
+        .map(|(member, class, dps)| MemberRecord {
+            class: class.clone(),
+            server: member.player_server.clone(),
+            dps,
+            buffs: member
+                .buff_uptime
+                .iter()
+                .filter_map(|buff| Some((buff.key.clone(), buff.value.parse().ok()?)))
+                .collect(),
+        })
+        .collect();
+    Some(EncounterRecord {
+        timestamp: fight.timestamp,
+        region: region.to_string(),
+        patch,
+        area_id: fight.area_id,
+        boss_id: fight.boss_id,
+        fight_duration: fight.fight_duration,
+        party_size: fight.members.len(),
+        members,
+    })
 }
 
+/// Count a fight rejected by the rules in the data-quality report
+pub fn store_rejected(log: RejectedLog, quality: &mut QualityReport, region_map: &RegionMap) {
+    let patch_name =
+        get_patch_name(region_map, &log.region, log.timestamp).unwrap_or_else(|| NO_PATCH.to_string());
+    quality.add_rejected_encounter(
+        &log.region,
+        &patch_name,
+        &Fight::new(log.area_id, log.boss_id).to_str(),
+        log.reason,
+        log.members as u64,
+    );
+}
+
+/// Statistics of one fight, region and patch. Medians and percentiles follow the configured method, except the legacy ones
 pub struct ExportResult {
+    /// Canonical class -> statistics of its members
     pub class: HashMap<Class, ExportClass>,
-    //pub healers_number: HashMap<u8, u32>,
+    /// Median clear time, in seconds
     pub clear_time_median: u64,
-    pub clear_time_percentile_90: u64,
-    //pub usage: HashMap<String, HashMap<String, u32>>
+    /// Time beaten by the fastest 10% of the clears
+    pub clear_time_fastest_10: u64,
+    /// For the legacy `clear_time` text files only
+    pub legacy_clear_time: KeyQuantiles,
+    /// Configured quantile -> clear time
+    pub clear_time_quantiles: Vec<(f64, u64)>,
+    /// Descriptive statistics of the clear times
+    pub clear_time_summary: Summary,
+    /// Role composition -> clears
+    pub role_compositions: BTreeMap<String, ExportComposition>,
+    /// Class composition -> clears
+    pub class_compositions: BTreeMap<String, ExportComposition>,
 }
 
+/// Clears of one party composition
+pub struct ExportComposition {
+    /// Clears of the composition
+    pub clears: u64,
+    /// Median clear time, in seconds
+    pub clear_time_median: u64,
+    /// Configured quantile -> clear time
+    pub clear_time_quantiles: Vec<(f64, u64)>,
+}
+
+/// Statistics of the members of one class
 pub struct ExportClass {
-    pub count: usize,
+    /// Members
+    pub count: u64,
+    /// Median dps
     pub dps_median: u32,
+    /// 90th percentile dps
     pub dps_percentile_90: u32,
+    /// For the legacy `dps_median` and `dps_percentile_90` text files only
+    pub legacy_dps: KeyQuantiles,
+    /// Configured quantile -> dps
+    pub dps_quantiles: Vec<(f64, u32)>,
+    /// Descriptive statistics of the dps
+    pub dps_summary: Summary,
+    /// Lower bound of the dps bucket -> members
     pub stepped_dps: HashMap<u32, u32>,
+    /// Buff id -> uptime among the members
+    pub buffs: BTreeMap<String, ExportBuff>,
+    /// Members with a skill log
+    pub skill_members: u64,
+    /// Skill id -> damage of the skill
+    pub skills: BTreeMap<String, ExportSkill>,
 }
 
-impl ExportResult {
-    fn new() -> ExportResult {
-        ExportResult {
-            class: HashMap::new(),
-            //healers_number: HashMap::new(),
-            //usage: HashMap::new(),
-            clear_time_median: 0,
-            clear_time_percentile_90: 0,
-        }
-    }
+/// Damage of a skill among the members of a class with a skill log
+pub struct ExportSkill {
+    /// Members using the skill
+    pub members: u64,
+    /// Damage of the skill over the damage of all the skills of the class, in %
+    pub damage_share: f64,
+    /// Mean of the damage percents sent by the meter, 0 when none was readable
+    pub damage_percent: f64,
+    /// Share of the damage of a member using the skill, in %
+    pub share_median: f64,
+    /// 90th percentile of the share of the damage of a member using the skill, in %
+    pub share_percentile_90: f64,
+    /// Hits of the skill
+    pub hits: u64,
+    /// Crit rate weighted by hits, in %
+    pub crit_rate: f64,
+    /// Highest crit seen
+    pub highest_crit: u64,
+    /// 0 when the skill never crit
+    pub lowest_crit: u64,
 }
 
-pub fn export(mut raw_data: DungeonData, class_map: &BidirMap<&str, Class>) -> ExportResult {
-    let mut result = ExportResult::new();
-    //result.healers_number = raw_data.healers_number;
-    raw_data.clear_time.sort();
-    result.clear_time_median = raw_data.clear_time[(raw_data.clear_time.len() / 2) as usize];
-    result.clear_time_percentile_90 =
-        raw_data.clear_time[((raw_data.clear_time.len() as f32 * 0.1) as usize)];
-    for class in class_map.iter_second_first() {
-        let mut data = match raw_data.members.remove(class) {
-            Some(t) => t,
-            None => continue,
-        };
-        data.dps.sort();
+/// Uptime of a buff among the members of a class, a member without the buff has an uptime of 0
+pub struct ExportBuff {
+    /// Members with an uptime above 0
+    pub members: u64,
+    /// Mean uptime (%)
+    pub mean: f64,
+    /// Median uptime (%)
+    pub median: u32,
+    /// Lower bound of the 10% wide uptime bucket -> members, 100% has its own bucket
+    pub histogram: BTreeMap<u32, u64>,
+}
+
+/// Statistics of the aggregates of one fight, region and patch
+pub fn export(mut raw_data: DungeonData, statistics: &Statistics) -> ExportResult {
+    let clear_time = raw_data.clear_time.key_quantiles(statistics.method);
+    let clear_time_quantiles = statistics
+        .quantiles
+        .iter()
+        .map(|&quantile| (quantile, raw_data.clear_time.quantile(quantile, statistics.method)))
+        .collect();
+    let mut result = ExportResult {
+        class: HashMap::new(),
+        clear_time_median: clear_time.median,
+        clear_time_fastest_10: clear_time.percentile_10,
+        legacy_clear_time: raw_data.clear_time.legacy_key_quantiles(),
+        clear_time_quantiles,
+        clear_time_summary: raw_data.clear_time.summary(statistics.trim, statistics.method),
+        role_compositions: export_compositions(raw_data.role_compositions, statistics),
+        class_compositions: export_compositions(raw_data.class_compositions, statistics),
+    };
+    for (class, mut data) in raw_data.members {
+        let count = data.dps.len();
+        let dps_quantiles = statistics
+            .quantiles
+            .iter()
+            .map(|&quantile| (quantile, data.dps.quantile(quantile, statistics.method) as u32))
+            .collect();
+        let dps = data.dps.key_quantiles(statistics.method);
         result.class.insert(
-            class.clone(),
+            class,
             ExportClass {
-                count: data.dps.len(),
-                dps_median: data.dps[(data.dps.len() / 2) as usize],
-                dps_percentile_90: data.dps[(data.dps.len() as f32 * 0.9) as usize],
+                count,
+                dps_median: dps.median as u32,
+                dps_percentile_90: dps.percentile_90 as u32,
+                legacy_dps: data.dps.legacy_key_quantiles(),
+                dps_quantiles,
+                dps_summary: data.dps.summary(statistics.trim, statistics.method),
                 stepped_dps: data.stepped_dps,
+                buffs: data
+                    .buffs
+                    .into_iter()
+                    .map(|(buff, uptimes)| (buff, export_buff(uptimes, count, statistics.method)))
+                    .collect(),
+                skill_members: data.skill_members,
+                skills: export_skills(data.skills, statistics.method),
             },
         );
     }
     result
 }
+
+// Members not in `uptimes` have an uptime of 0
+fn export_buff(mut uptimes: HashMap<u32, u64>, members: u64, method: QuantileMethod) -> ExportBuff {
+    let with_buff: u64 = uptimes.values().sum();
+    let members_with_buff = with_buff - uptimes.get(&0).unwrap_or(&0);
+    let absent = members.saturating_sub(with_buff);
+    if absent > 0 {
+        *(uptimes.entry(0).or_insert(0)) += absent;
+    }
+    let mut histogram = BTreeMap::new();
+    let mut total = 0;
+    for (&uptime, &count) in &uptimes {
+        total += u64::from(uptime) * count;
+        *(histogram.entry((uptime / 10 * 10).min(100)).or_insert(0)) += count;
+    }
+    ExportBuff {
+        members: members_with_buff,
+        mean: if members == 0 {
+            0.0
+        } else {
+            total as f64 / members as f64
+        },
+        median: if uptimes.is_empty() {
+            0
+        } else {
+            distribution::histogram_quantile(&uptimes, 0.5, method) as u32
+        },
+        histogram,
+    }
+}
+
+fn export_skills(
+    skills: HashMap<String, SkillDetails>,
+    method: QuantileMethod,
+) -> BTreeMap<String, ExportSkill> {
+    let total_damage: u64 = skills.values().map(|details| details.damage).sum();
+    skills
+        .into_iter()
+        .map(|(skill, details)| {
+            let members = details.share.values().sum();
+            let share = |q| distribution::histogram_quantile(&details.share, q, method) as f64 / 10.0;
+            let result = ExportSkill {
+                members,
+                damage_share: if total_damage == 0 {
+                    0.0
+                } else {
+                    details.damage as f64 * 100.0 / total_damage as f64
+                },
+                damage_percent: if details.damage_percent_members == 0 {
+                    0.0
+                } else {
+                    details.damage_percent / details.damage_percent_members as f64
+                },
+                share_median: share(0.5),
+                share_percentile_90: share(0.9),
+                hits: details.hits,
+                crit_rate: if details.hits == 0 {
+                    0.0
+                } else {
+                    details.crit_hits / details.hits as f64
+                },
+                highest_crit: details.highest_crit,
+                lowest_crit: details.lowest_crit,
+            };
+            (skill, result)
+        })
+        .collect()
+}
+
+fn export_compositions(
+    compositions: HashMap<String, Distribution>,
+    statistics: &Statistics,
+) -> BTreeMap<String, ExportComposition> {
+    compositions
+        .into_iter()
+        .map(|(composition, mut clear_time)| {
+            let clears = clear_time.len();
+            let clear_time_quantiles = statistics
+                .quantiles
+                .iter()
+                .map(|&quantile| (quantile, clear_time.quantile(quantile, statistics.method)))
+                .collect();
+            let result = ExportComposition {
+                clears,
+                clear_time_median: clear_time.key_quantiles(statistics.method).median,
+                clear_time_quantiles,
+            };
+            (composition, result)
+        })
+        .collect()
+}
+
+/// "10" for 0.1, "99.9" for 0.999
+pub fn quantile_name(quantile: f64) -> String {
+    format!("{}", (quantile * 1000.0).round() / 10.0)
+}
+
+/// Statistic name -> formatted value, in a stable order
+pub fn summary_values(summary: &Summary) -> Vec<(&'static str, String)> {
+    vec![
+        ("count", summary.count.to_string()),
+        ("mean", format!("{:.2}", summary.mean)),
+        ("std_dev", format!("{:.2}", summary.std_dev)),
+        ("min", summary.min.to_string()),
+        ("max", summary.max.to_string()),
+        ("iqr", summary.iqr.to_string()),
+        ("trimmed_mean", format!("{:.2}", summary.trimmed_mean)),
+        ("cv", format!("{:.4}", summary.cv)),
+    ]
+}
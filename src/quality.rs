@@ -0,0 +1,179 @@
+//! Counts of the accepted and rejected encounters and members, and of the invalid files.
+
+use std::collections::HashMap;
+
+/// Patch name of the encounters outside every patch window
+pub const NO_PATCH: &str = "none";
+
+/// Encounters and members
+#[derive(Serialize, Deserialize, Default)]
+pub struct Counter {
+    /// Encounters
+    pub encounters: u64,
+    /// Members
+    pub members: u64,
+}
+
+/// Accepted and rejected encounters of one fight, region and patch
+#[derive(Serialize, Deserialize, Default)]
+pub struct FightQuality {
+    /// Accepted encounters, and their members
+    pub accepted: Counter,
+    /// Reason -> rejected encounters and members
+    pub rejected: HashMap<String, Counter>,
+}
+
+/// Data-quality report: what was kept and what was dropped, and why
+#[derive(Serialize, Deserialize, Default)]
+pub struct QualityReport {
+    /// Files parsed, valid or not
+    pub files_processed: u64,
+    /// Reason, see `ParseError::reason` -> invalid files
+    pub files_failed: HashMap<String, u64>,
+    /// Error of every invalid file
+    pub failed_files: Vec<String>,
+    /// Accepted encounters, and their members
+    pub accepted: Counter,
+    /// Reason -> rejected encounters, or members left out of accepted encounters
+    pub rejected: HashMap<String, Counter>,
+    /// Raw class -> members of a class missing from the configuration
+    pub unknown_classes: HashMap<String, u64>,
+    /// region -> patch -> fight
+    pub regions: HashMap<String, HashMap<String, HashMap<String, FightQuality>>>,
+}
+
+impl QualityReport {
+    /// Empty report
+    pub fn new() -> QualityReport {
+        QualityReport::default()
+    }
+
+    /// Count a valid file
+    pub fn add_file(&mut self) {
+        self.files_processed += 1;
+    }
+
+    /// Count an invalid file, with its error
+    pub fn add_failed_file(&mut self, reason: &str, error: String) {
+        self.files_processed += 1;
+        *(self.files_failed.entry(reason.to_string()).or_insert(0)) += 1;
+        self.failed_files.push(error);
+    }
+
+    /// Count an accepted encounter and its members
+    pub fn add_accepted(&mut self, region: &str, patch: &str, fight: &str, members: u64) {
+        add(&mut self.accepted, 1, members);
+        add(
+            &mut self.fight(region, patch, fight).accepted,
+            1,
+            members,
+        );
+    }
+
+    /// Count an encounter rejected for `reason`, and its members
+    pub fn add_rejected_encounter(
+        &mut self,
+        region: &str,
+        patch: &str,
+        fight: &str,
+        reason: &str,
+        members: u64,
+    ) {
+        add(
+            self.rejected.entry(reason.to_string()).or_default(),
+            1,
+            members,
+        );
+        add(
+            self.fight(region, patch, fight)
+                .rejected
+                .entry(reason.to_string())
+                .or_default(),
+            1,
+            members,
+        );
+    }
+
+    /// Count a member of an accepted encounter left out of the statistics for `reason`
+    pub fn add_rejected_member(&mut self, region: &str, patch: &str, fight: &str, reason: &str) {
+        add(
+            self.rejected.entry(reason.to_string()).or_default(),
+            0,
+            1,
+        );
+        add(
+            self.fight(region, patch, fight)
+                .rejected
+                .entry(reason.to_string())
+                .or_default(),
+            0,
+            1,
+        );
+    }
+
+    /// Count a member whose raw class is unknown
+    pub fn add_unknown_class(&mut self, class: &str) {
+        *(self.unknown_classes.entry(class.to_string()).or_insert(0)) += 1;
+    }
+
+    /// Print the rejected encounters and members, and the unknown classes
+    pub fn print(&self) {
+        for (reason, counter) in &self.rejected {
+            println!(
+                "Rejected ({}): {} encounters, {} members",
+                reason, counter.encounters, counter.members
+            );
+        }
+        for (class, count) in &self.unknown_classes {
+            println!("Unknown class {}: {} members skipped", class, count);
+        }
+    }
+
+    /// Add the counters of another report, as if both inputs were processed together
+    pub fn merge(&mut self, other: QualityReport) {
+        self.files_processed += other.files_processed;
+        for (reason, count) in other.files_failed {
+            *(self.files_failed.entry(reason).or_insert(0)) += count;
+        }
+        self.failed_files.extend(other.failed_files);
+        merge_counter(&mut self.accepted, other.accepted);
+        merge_counters(&mut self.rejected, other.rejected);
+        for (class, count) in other.unknown_classes {
+            *(self.unknown_classes.entry(class).or_insert(0)) += count;
+        }
+        for (region, patches) in other.regions {
+            for (patch, fights) in patches {
+                for (fight, other_quality) in fights {
+                    let quality = self.fight(&region, &patch, &fight);
+                    merge_counter(&mut quality.accepted, other_quality.accepted);
+                    merge_counters(&mut quality.rejected, other_quality.rejected);
+                }
+            }
+        }
+    }
+
+    fn fight(&mut self, region: &str, patch: &str, fight: &str) -> &mut FightQuality {
+        self.regions
+            .entry(region.to_string())
+            .or_default()
+            .entry(patch.to_string())
+            .or_default()
+            .entry(fight.to_string())
+            .or_default()
+    }
+}
+
+fn add(counter: &mut Counter, encounters: u64, members: u64) {
+    counter.encounters += encounters;
+    counter.members += members;
+}
+
+fn merge_counter(counter: &mut Counter, other: Counter) {
+    add(counter, other.encounters, other.members);
+}
+
+fn merge_counters(counters: &mut HashMap<String, Counter>, other: HashMap<String, Counter>) {
+    for (reason, counter) in other {
+        merge_counter(counters.entry(reason).or_default(), counter);
+    }
+}
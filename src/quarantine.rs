@@ -0,0 +1,187 @@
+//! Invalid input files moved aside with the reason they were rejected, and parsed again on demand.
+
+use error::ParseError;
+use glob::glob;
+use parse;
+use rules::Rule;
+use serde_json;
+use std::{
+    fs,
+    fs::File,
+    io::prelude::*,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Sidecar written next to a quarantined file
+#[derive(Serialize, Deserialize)]
+struct QuarantineRecord {
+    original_path: String,
+    #[serde(default)]
+    reason: String,
+    error: String,
+    quarantined_at: u64,
+}
+
+fn sidecar_path(file: &Path) -> PathBuf {
+    let mut sidecar = file.as_os_str().to_owned();
+    sidecar.push(".error.json");
+    PathBuf::from(sidecar)
+}
+
+/// Move a file which failed to parse into the quarantine directory, keeping its path relative to the source
+pub fn quarantine(
+    filename: &str,
+    source: &str,
+    quarantine_dir: &str,
+    error: &ParseError,
+) -> Result<(), String> {
+    let path = Path::new(filename);
+    let relative = match path.strip_prefix(source) {
+        Ok(t) => t,
+        Err(_) => Path::new(path.file_name().unwrap_or(path.as_os_str())),
+    };
+    let destination = Path::new(quarantine_dir).join(relative);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Unable to create {}: {}", parent.display(), e))?;
+    }
+    move_file(path, &destination)?;
+    let record = QuarantineRecord {
+        original_path: filename.to_string(),
+        reason: error.reason().to_string(),
+        error: error.to_string(),
+        quarantined_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+    write_record(&destination, &record)
+}
+
+/// Parse again every quarantined file, and move back to their original place the ones which are now valid
+pub fn retry(quarantine_dir: &str, rules: &[Rule]) -> (u32, u32) {
+    let mut restored = 0;
+    let mut still_invalid = 0;
+    let search = format!("{}/**/*.xz", quarantine_dir);
+    for entry in glob(&search).expect("Failed to read glob pattern") {
+        let path = match entry {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        let record = match read_record(&path) {
+            Ok(t) => t,
+            Err(err) => {
+                println!("{}", err);
+                still_invalid += 1;
+                continue;
+            }
+        };
+        match parse::read_file(&path.to_string_lossy(), rules, |_| {}) {
+            Ok(_) => {
+                let original = Path::new(&record.original_path);
+                let result = original
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .map_err(|e| format!("Unable to restore {}: {}", original.display(), e))
+                    .and_then(|_| move_file(&path, original));
+                match result {
+                    Ok(_) => {
+                        let _ = fs::remove_file(sidecar_path(&path));
+                        println!("Restored {}", record.original_path);
+                        restored += 1;
+                    }
+                    Err(err) => {
+                        println!("{}", err);
+                        still_invalid += 1;
+                    }
+                }
+            }
+            Err(err) => {
+                println!("{}", err);
+                let record = QuarantineRecord {
+                    reason: err.reason().to_string(),
+                    error: err.to_string(),
+                    ..record
+                };
+                if let Err(err) = write_record(&path, &record) {
+                    println!("{}", err);
+                }
+                still_invalid += 1;
+            }
+        }
+    }
+    (restored, still_invalid)
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<(), String> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    // rename does not work across file systems
+    fs::copy(from, to)
+        .and_then(|_| fs::remove_file(from))
+        .map_err(|e| format!("Unable to move {} to {}: {}", from.display(), to.display(), e))
+}
+
+fn write_record(file: &Path, record: &QuarantineRecord) -> Result<(), String> {
+    let sidecar = sidecar_path(file);
+    File::create(&sidecar)
+        .and_then(|mut f| f.write_all(serde_json::to_string_pretty(record).unwrap().as_bytes()))
+        .map_err(|e| format!("Unable to write {}: {}", sidecar.display(), e))
+}
+
+fn read_record(file: &Path) -> Result<QuarantineRecord, String> {
+    let sidecar = sidecar_path(file);
+    let mut content = String::new();
+    File::open(&sidecar)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| format!("Unable to read {}: {}", sidecar.display(), e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Unable to parse {}: {}", sidecar.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate xz2;
+    use self::xz2::write::XzEncoder;
+    use super::{quarantine, read_record, retry, sidecar_path};
+    use parse;
+    use std::{env, fs, io::prelude::*, process};
+
+    #[test]
+    fn quarantined_file_is_restored_once_valid() {
+        let directory = env::temp_dir().join(format!("quarantine-{}", process::id()));
+        let source = directory.join("source");
+        let quarantine_dir = directory.join("quarantine");
+        let original = source.join("EU").join("fights.xz");
+        fs::create_dir_all(original.parent().unwrap()).unwrap();
+        fs::write(&original, "not xz").unwrap();
+        let filename = original.to_str().unwrap();
+        let error = parse::read_file(filename, &[], |_| {}).unwrap_err();
+        quarantine(
+            filename,
+            source.to_str().unwrap(),
+            quarantine_dir.to_str().unwrap(),
+            &error,
+        )
+        .unwrap();
+        let quarantined = quarantine_dir.join("EU").join("fights.xz");
+        assert!(!original.exists() && quarantined.exists());
+        let record = read_record(&quarantined).unwrap();
+        assert_eq!(record.original_path, filename);
+        assert_eq!(record.reason, "decompression_error");
+
+        // Still invalid, the sidecar is kept
+        assert_eq!(retry(quarantine_dir.to_str().unwrap(), &[]), (0, 1));
+        assert!(quarantined.exists() && sidecar_path(&quarantined).exists());
+
+        // Readable now, as if the parser had been fixed
+        let mut encoder = XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(b"[]").unwrap();
+        fs::write(&quarantined, encoder.finish().unwrap()).unwrap();
+        assert_eq!(retry(quarantine_dir.to_str().unwrap(), &[]), (1, 0));
+        assert!(original.exists());
+        assert!(!quarantined.exists() && !sidecar_path(&quarantined).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! Comparison of the statistics of two patches, or two regions, of the aggregates.
+
+extern crate serde;
+use self::serde::Serialize;
+use analyse::SNAPSHOT_FILENAME;
+use config::{Config, RegionMap, Statistics};
+use json_export;
+use process::{self, Class, ExportResult, GlobalData};
+use state::State;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+};
+
+/// Region and patch of the aggregates, written `<region>/<patch>`
+#[derive(Serialize)]
+pub struct PatchKey {
+    /// Region
+    pub region: String,
+    /// Patch, named in the configuration
+    pub patch: String,
+}
+
+impl PatchKey {
+    /// Parse `<region>/<patch>`, which must be a patch of the configuration
+    pub fn new(name: &str, region_map: &RegionMap) -> Result<PatchKey, String> {
+        let mut parts = name.splitn(2, '/');
+        let region = parts.next().unwrap_or("");
+        let patch = parts
+            .next()
+            .ok_or_else(|| format!("Invalid patch {}, expected <region>/<patch>", name))?;
+        if !region_map
+            .get(region)
+            .is_some_and(|patches| patches.contains_key(patch))
+        {
+            return Err(format!("Unknown patch {} of region {}", patch, region));
+        }
+        Ok(PatchKey {
+            region: region.to_string(),
+            patch: patch.to_string(),
+        })
+    }
+
+    fn to_str(&self) -> String {
+        format!("{} {}", self.region, self.patch)
+    }
+}
+
+/// Comparison of the statistics of two patches, or of two regions
+#[derive(Serialize)]
+pub struct Comparison {
+    /// Patch the other one is compared with
+    pub base: PatchKey,
+    /// Compared patch
+    pub other: PatchKey,
+    /// Relative change above which a value is flagged
+    pub threshold: f64,
+    /// Sorted by fight
+    pub fights: Vec<FightComparison>,
+}
+
+/// Both sides of one fight
+#[derive(Serialize)]
+pub struct FightComparison {
+    /// `<area>-<boss>`
+    pub fight: String,
+    /// Area of the fight
+    pub area_id: u32,
+    /// Boss of the fight
+    pub boss_id: u32,
+    /// Median clear time, in seconds
+    pub clear_time_median: Delta,
+    /// Canonical class -> its comparison
+    pub classes: BTreeMap<String, ClassComparison>,
+}
+
+/// Both sides of one class of a fight
+#[derive(Serialize)]
+pub struct ClassComparison {
+    /// Median dps
+    pub dps_median: Delta,
+    /// 90th percentile dps
+    pub dps_percentile_90: Delta,
+    /// Fraction of the members of the fight playing the class
+    pub share: Delta,
+}
+
+/// A value is missing when the fight or the class has no member on that side
+#[derive(Serialize)]
+pub struct Delta {
+    /// Value of the base side
+    pub base: Option<f64>,
+    /// Value of the other side
+    pub other: Option<f64>,
+    /// other - base
+    pub change: Option<f64>,
+    /// change / base
+    pub relative_change: Option<f64>,
+    /// The relative change is larger than the threshold, or the value is missing on one side only
+    pub flagged: bool,
+}
+
+impl Delta {
+    fn new(base: Option<f64>, other: Option<f64>, threshold: f64) -> Delta {
+        let change = match (base, other) {
+            (Some(base), Some(other)) => Some(other - base),
+            _ => None,
+        };
+        let relative_change = match (change, base) {
+            (Some(change), Some(base)) if base != 0.0 => Some(change / base),
+            _ => None,
+        };
+        let flagged = match relative_change {
+            Some(relative_change) => relative_change.abs() > threshold,
+            None => base.is_some() != other.is_some(),
+        };
+        Delta {
+            base,
+            other,
+            change,
+            relative_change,
+            flagged,
+        }
+    }
+}
+
+// Values compared for one key of a fight, medians and percentiles with the configured method, as in dps_quantiles/
+struct Side {
+    clear_time_median: f64,
+    /// class -> (median dps, 90th percentile dps, share)
+    classes: HashMap<Class, (f64, f64, f64)>,
+}
+
+impl Side {
+    fn new(result: ExportResult) -> Side {
+        let members: u64 = result.class.values().map(|data| data.count).sum();
+        Side {
+            clear_time_median: result.clear_time_median as f64,
+            classes: result
+                .class
+                .into_iter()
+                .map(|(class, data)| {
+                    let values = (
+                        f64::from(data.dps_median),
+                        f64::from(data.dps_percentile_90),
+                        data.count as f64 / members as f64,
+                    );
+                    (class, values)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Compare, fight by fight, the statistics of two different keys of the aggregates
+pub fn compare(
+    raw_data: GlobalData,
+    base: PatchKey,
+    other: PatchKey,
+    statistics: &Statistics,
+    threshold: f64,
+) -> Comparison {
+    let base_key = process::get_key(&base.region, &base.patch);
+    let other_key = process::get_key(&other.region, &other.patch);
+    let mut fights = Vec::new();
+    for (fight, mut fight_data) in raw_data.fights {
+        let export = |data| Side::new(process::export(data, statistics));
+        let base_side = fight_data.remove(&base_key).map(export);
+        let other_side = fight_data.remove(&other_key).map(export);
+        if base_side.is_none() && other_side.is_none() {
+            continue;
+        }
+        let (base_side, other_side) = (base_side.as_ref(), other_side.as_ref());
+        let class_names: BTreeSet<&Class> = base_side
+            .iter()
+            .chain(other_side.iter())
+            .flat_map(|side| side.classes.keys())
+            .collect();
+        let mut classes = BTreeMap::new();
+        for class in class_names {
+            let base_values = base_side.and_then(|side| side.classes.get(class));
+            let other_values = other_side.and_then(|side| side.classes.get(class));
+            classes.insert(
+                class.clone(),
+                ClassComparison {
+                    dps_median: Delta::new(
+                        base_values.map(|values| values.0),
+                        other_values.map(|values| values.0),
+                        threshold,
+                    ),
+                    dps_percentile_90: Delta::new(
+                        base_values.map(|values| values.1),
+                        other_values.map(|values| values.1),
+                        threshold,
+                    ),
+                    share: Delta::new(
+                        base_values.map(|values| values.2),
+                        other_values.map(|values| values.2),
+                        threshold,
+                    ),
+                },
+            );
+        }
+        fights.push(FightComparison {
+            fight: fight.to_str(),
+            area_id: fight.area_id,
+            boss_id: fight.boss_id,
+            clear_time_median: Delta::new(
+                base_side.map(|side| side.clear_time_median),
+                other_side.map(|side| side.clear_time_median),
+                threshold,
+            ),
+            classes,
+        });
+    }
+    fights.sort_by_key(|fight| (fight.area_id, fight.boss_id));
+    Comparison {
+        base,
+        other,
+        threshold,
+        fights,
+    }
+}
+
+/// Markdown version of the comparison, flagged changes in bold
+pub fn markdown(comparison: &Comparison) -> String {
+    let mut result = format!(
+        "# {} compared with {}\n\nChanges larger than {}% are in bold.\n",
+        comparison.other.to_str(),
+        comparison.base.to_str(),
+        comparison.threshold * 100.0
+    );
+    for fight in &comparison.fights {
+        result.push_str(&format!(
+            "\n## {}\n\nMedian clear time (s): {}\n\n",
+            fight.fight,
+            cell(&fight.clear_time_median, |value| format!("{}", value))
+        ));
+        result.push_str("| Class | Median dps | 90th percentile dps | Share |\n");
+        result.push_str("|---|---|---|---|\n");
+        for (class, class_comparison) in &fight.classes {
+            result.push_str(&format!(
+                "| {} | {} | {} | {} |\n",
+                class,
+                cell(&class_comparison.dps_median, |value| format!("{}", value)),
+                cell(&class_comparison.dps_percentile_90, |value| format!("{}", value)),
+                cell(&class_comparison.share, |value| format!("{:.1}%", value * 100.0)),
+            ));
+        }
+    }
+    result
+}
+
+// "base -> other (+x%)", "-" for a missing value
+fn cell<F: Fn(f64) -> String>(delta: &Delta, format: F) -> String {
+    let value = |value: Option<f64>| value.map_or_else(|| "-".to_string(), &format);
+    let mut cell = format!("{} -> {}", value(delta.base), value(delta.other));
+    if let Some(relative_change) = delta.relative_change {
+        cell.push_str(&format!(" ({:+.1}%)", relative_change * 100.0));
+    }
+    if delta.flagged {
+        cell = format!("**{}**", cell);
+    }
+    cell
+}
+
+/// Compare two keys of the aggregates saved in `target`, and write the report there,
+/// in `comparison/<base>_<other>.json` and `.md`
+pub fn write_report(
+    config: &Config,
+    target: &str,
+    base: PatchKey,
+    other: PatchKey,
+    fingerprint: &str,
+) -> Result<(), String> {
+    if base.region == other.region && base.patch == other.patch {
+        return Err(format!("{} is compared with itself", base.to_str()));
+    }
+    let state = State::load(&format!("{}/{}", target, SNAPSHOT_FILENAME), fingerprint)?;
+    let name = format!(
+        "{}/comparison/{}-{}_{}-{}",
+        target, base.region, base.patch, other.region, other.patch
+    );
+    let comparison = compare(
+        state.data,
+        base,
+        other,
+        &config.statistics,
+        config.report.threshold,
+    );
+    fs::create_dir_all(format!("{}/comparison", target))
+        .map_err(|e| format!("Unable to create {}/comparison: {}", target, e))?;
+    for (filename, content) in &[
+        (format!("{}.json", name), json_export::to_string(&comparison)),
+        (format!("{}.md", name), markdown(&comparison)),
+    ] {
+        fs::write(filename, content).map_err(|e| format!("Unable to write {}: {}", filename, e))?;
+        println!("{}", filename);
+    }
+    Ok(())
+}
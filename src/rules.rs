@@ -0,0 +1,264 @@
+//! Rules rejecting the fights which must not count in the statistics.
+
+use parse::{Members, StatsLog};
+
+/// A rule describes a condition for which a fight is rejected.
+/// Buff uptimes are percentages, a buff missing from a member counts as 0.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Rule {
+    /// Any member has one of the buffs
+    BuffPresent {
+        /// Buff ids
+        buffs: Vec<String>,
+    },
+    /// Any member has the buff uptime above the threshold
+    BuffUptimeAbove {
+        /// Buff id
+        buff: String,
+        /// Uptime (%)
+        threshold: i64,
+    },
+    /// Any member has the buff uptime below the threshold
+    BuffUptimeBelow {
+        /// Buff id
+        buff: String,
+        /// Uptime (%)
+        threshold: i64,
+    },
+    /// Any member of the region is on another server
+    ServerWhitelist {
+        /// Region the rule applies to
+        region: String,
+        /// Allowed servers
+        servers: Vec<String>,
+    },
+    /// Any member of the region is on one of the servers
+    ServerBlacklist {
+        /// Region the rule applies to
+        region: String,
+        /// Forbidden servers
+        servers: Vec<String>,
+    },
+    /// Party size out of the bounds
+    PartySize {
+        /// Smallest party allowed, no bound when None
+        min: Option<usize>,
+        /// Largest party allowed, no bound when None
+        max: Option<usize>,
+    },
+    /// Fight duration out of the bounds
+    FightDuration {
+        /// Shortest fight allowed in seconds, no bound when None
+        min: Option<u64>,
+        /// Longest fight allowed in seconds, no bound when None
+        max: Option<u64>,
+    },
+    /// Area not in the list
+    AreaWhitelist {
+        /// Allowed areas
+        areas: Vec<u32>,
+    },
+    /// Area in the list
+    AreaBlacklist {
+        /// Forbidden areas
+        areas: Vec<u32>,
+    },
+}
+
+impl Rule {
+    /// True when the fight matches the condition of the rule
+    pub fn rejects(&self, stat: &StatsLog) -> bool {
+        let members = &stat.content.members;
+        match self {
+            Rule::BuffPresent { buffs } => members
+                .iter()
+                .any(|member| member.buff_uptime.iter().any(|buff| buffs.contains(&buff.key))),
+            Rule::BuffUptimeAbove { buff, threshold } => members
+                .iter()
+                .any(|member| buff_uptime(member, buff) > *threshold),
+            Rule::BuffUptimeBelow { buff, threshold } => members
+                .iter()
+                .any(|member| buff_uptime(member, buff) < *threshold),
+            Rule::ServerWhitelist { region, servers } => {
+                stat.region() == region
+                    && members
+                        .iter()
+                        .any(|member| !servers.contains(&member.player_server))
+            }
+            Rule::ServerBlacklist { region, servers } => {
+                stat.region() == region
+                    && members
+                        .iter()
+                        .any(|member| servers.contains(&member.player_server))
+            }
+            Rule::PartySize { min, max } => out_of_bounds(members.len(), min, max),
+            Rule::FightDuration { min, max } => {
+                out_of_bounds(stat.content.fight_duration, min, max)
+            }
+            Rule::AreaWhitelist { areas } => !areas.contains(&stat.content.area_id),
+            Rule::AreaBlacklist { areas } => areas.contains(&stat.content.area_id),
+        }
+    }
+
+    /// Reason reported for the fights rejected by the rule, several rules share one
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rule::BuffPresent { .. }
+            | Rule::BuffUptimeAbove { .. }
+            | Rule::BuffUptimeBelow { .. } => "forbidden_buff",
+            Rule::ServerWhitelist { .. } | Rule::ServerBlacklist { .. } => "forbidden_server",
+            Rule::PartySize { .. } => "party_size",
+            Rule::FightDuration { .. } => "fight_duration",
+            Rule::AreaWhitelist { .. } | Rule::AreaBlacklist { .. } => "forbidden_area",
+        }
+    }
+}
+
+/// Returns the first rule rejecting the fight, if any
+pub fn find_rejecting_rule<'a>(rules: &'a [Rule], stat: &StatsLog) -> Option<&'a Rule> {
+    rules.iter().find(|rule| rule.rejects(stat))
+}
+
+/// Uptime (%) of a buff of the member, 0 when the member does not have it
+pub fn buff_uptime(member: &Members, buff_id: &str) -> i64 {
+    member
+        .buff_uptime
+        .iter()
+        .find(|buff| buff.key == buff_id)
+        .and_then(|buff| buff.value.parse().ok())
+        .unwrap_or(0)
+}
+
+fn out_of_bounds<T: PartialOrd>(value: T, min: &Option<T>, max: &Option<T>) -> bool {
+    if let Some(min) = min {
+        if value < *min {
+            return true;
+        }
+    }
+    if let Some(max) = max {
+        if value > *max {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_rejecting_rule, Rule};
+    use parse::StatsLog;
+    use serde_json;
+
+    type Member<'a> = (&'a str, &'a [(&'a str, &'a str)]);
+
+    const NO_BUFF: Member = ("Killian", &[]);
+
+    // Fight of the EU region, each member being `(server, [(buff, uptime)])`
+    fn fight(area_id: u32, duration: u64, members: &[Member]) -> StatsLog {
+        let members: Vec<String> = members
+            .iter()
+            .map(|&(server, buffs)| {
+                let buffs: Vec<String> = buffs
+                    .iter()
+                    .map(|&(key, value)| format!(r#"{{"Key": "{}", "Value": "{}"}}"#, key, value))
+                    .collect();
+                format!(
+                    r#"{{"buffUptime": [{}], "playerClass": "Warrior", "playerDps": "1000", "playerServer": "{}"}}"#,
+                    buffs.join(","),
+                    server
+                )
+            })
+            .collect();
+        serde_json::from_str(&format!(
+            r#"{{"content": {{"areaId": {}, "bossId": 1000, "fightDuration": {}, "timestamp": 1561777197,
+                "members": [{}]}}, "directory": "EU.1"}}"#,
+            area_id,
+            duration,
+            members.join(",")
+        ))
+        .unwrap()
+    }
+
+    fn rule(json: &str) -> Rule {
+        serde_json::from_str(json).unwrap()
+    }
+
+    // Two members, only the second one has the buff 8888889
+    fn uptime(value: &str) -> StatsLog {
+        fight(444, 300, &[NO_BUFF, ("Killian", &[("8888889", value)])])
+    }
+
+    #[test]
+    fn buff_present_rejects_any_member_with_one_of_the_buffs() {
+        let rule = rule(r#"{"type": "buff_present", "buffs": ["1", "8888889"]}"#);
+        assert!(rule.rejects(&uptime("0")));
+        assert!(!rule.rejects(&fight(444, 300, &[("Killian", &[("2", "100")])])));
+    }
+
+    #[test]
+    fn buff_uptime_above_rejects_strictly_above_the_threshold() {
+        let rule = rule(r#"{"type": "buff_uptime_above", "buff": "8888889", "threshold": 50}"#);
+        assert!(!rule.rejects(&uptime("50")));
+        assert!(rule.rejects(&uptime("51")));
+        // Unreadable uptimes count as 0
+        assert!(!rule.rejects(&uptime("lots")));
+    }
+
+    #[test]
+    fn buff_uptime_below_rejects_strictly_below_the_threshold_missing_buffs_included() {
+        let rule = rule(r#"{"type": "buff_uptime_below", "buff": "8888889", "threshold": 50}"#);
+        let both = |value| fight(444, 300, &[("Killian", &[("8888889", "100")]), ("Killian", &[("8888889", value)])]);
+        assert!(!rule.rejects(&both("50")));
+        assert!(rule.rejects(&both("49")));
+        // The first member does not have the buff
+        assert!(rule.rejects(&uptime("100")));
+    }
+
+    #[test]
+    fn server_lists_only_apply_to_their_region() {
+        let whitelist = rule(r#"{"type": "server_whitelist", "region": "EU", "servers": ["Killian"]}"#);
+        assert!(!whitelist.rejects(&uptime("0")));
+        assert!(whitelist.rejects(&fight(444, 300, &[NO_BUFF, ("Yurian", &[])])));
+        let blacklist = rule(r#"{"type": "server_blacklist", "region": "EU", "servers": ["Yurian"]}"#);
+        assert!(!blacklist.rejects(&uptime("0")));
+        assert!(blacklist.rejects(&fight(444, 300, &[NO_BUFF, ("Yurian", &[])])));
+        let other_region = rule(r#"{"type": "server_blacklist", "region": "NA", "servers": ["Killian"]}"#);
+        assert!(!other_region.rejects(&uptime("0")));
+    }
+
+    #[test]
+    fn bounds_are_inclusive_and_optional() {
+        let party_size = rule(r#"{"type": "party_size", "min": 2, "max": 3}"#);
+        assert!(party_size.rejects(&fight(444, 300, &[NO_BUFF])));
+        assert!(!party_size.rejects(&uptime("0")));
+        assert!(party_size.rejects(&fight(444, 300, &[NO_BUFF; 4])));
+        let fight_duration = rule(r#"{"type": "fight_duration", "min": 300}"#);
+        assert!(!fight_duration.rejects(&fight(444, 300, &[NO_BUFF])));
+        assert!(fight_duration.rejects(&fight(444, 299, &[NO_BUFF])));
+        assert!(!fight_duration.rejects(&fight(444, u64::MAX, &[NO_BUFF])));
+    }
+
+    #[test]
+    fn area_lists() {
+        let whitelist = rule(r#"{"type": "area_whitelist", "areas": [444]}"#);
+        assert!(!whitelist.rejects(&fight(444, 300, &[NO_BUFF])));
+        assert!(whitelist.rejects(&fight(9920, 300, &[NO_BUFF])));
+        let blacklist = rule(r#"{"type": "area_blacklist", "areas": [444]}"#);
+        assert!(blacklist.rejects(&fight(444, 300, &[NO_BUFF])));
+        assert!(!blacklist.rejects(&fight(9920, 300, &[NO_BUFF])));
+    }
+
+    #[test]
+    fn first_rejecting_rule_gives_the_reason() {
+        let rules = vec![
+            rule(r#"{"type": "area_blacklist", "areas": [9920]}"#),
+            rule(r#"{"type": "party_size", "max": 1}"#),
+            rule(r#"{"type": "buff_present", "buffs": ["8888889"]}"#),
+        ];
+        assert_eq!(find_rejecting_rule(&rules, &uptime("0")).map(Rule::reason), Some("party_size"));
+        let alone = fight(444, 300, &[("Killian", &[("8888889", "1")])]);
+        assert_eq!(find_rejecting_rule(&rules, &alone).map(Rule::reason), Some("forbidden_buff"));
+        assert!(find_rejecting_rule(&rules, &fight(444, 300, &[NO_BUFF])).is_none());
+    }
+}
@@ -0,0 +1,230 @@
+//! Class dps split on the uptime of a buff.
+
+use config::{Config, Statistics};
+use distribution::{Backend, Distribution};
+use parse::{Members, StatsLog};
+use process::{accepted_members, get_key, get_patch_name, quantile_name, Class, Fight};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+
+/// Split of the class dps on the uptime of a buff
+#[derive(Deserialize, Serialize)]
+pub struct Segment {
+    /// Buff id
+    pub buff: String,
+    /// Uptime (%) from which a member counts as having the buff
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+}
+
+fn default_threshold() -> u32 {
+    1
+}
+
+impl Segment {
+    /// `<buff>-<threshold>`, as used in the output paths
+    pub fn name(&self) -> String {
+        format!("{}-{}", self.buff, self.threshold)
+    }
+
+    /// A member without the buff has an uptime of 0
+    fn applies(&self, member: &Members) -> bool {
+        member
+            .buff_uptime
+            .iter()
+            .filter(|buff| buff.key == self.buff)
+            .filter_map(|buff| buff.value.parse::<u32>().ok())
+            .any(|uptime| uptime >= self.threshold)
+    }
+}
+
+/// Dps of the members of a class with and without the buff
+#[derive(Serialize, Deserialize)]
+pub struct SegmentData {
+    /// Members with an uptime of at least the threshold
+    pub with: Distribution,
+    /// The other members
+    pub without: Distribution,
+}
+
+impl SegmentData {
+    fn new(backend: Backend) -> SegmentData {
+        SegmentData {
+            with: Distribution::new(backend),
+            without: Distribution::new(backend),
+        }
+    }
+
+    fn merge(&mut self, other: SegmentData) {
+        self.with.merge(other.with);
+        self.without.merge(other.without);
+    }
+}
+
+/// fight -> region and patch key -> segment name -> class -> data
+pub type SegmentMap = HashMap<Fight, HashMap<String, HashMap<String, HashMap<Class, SegmentData>>>>;
+
+/// Add the members of an accepted encounter to every configured segment, with the same members as process::store
+pub fn store(content: &StatsLog, segments: &mut SegmentMap, config: &Config, backend: Backend) {
+    if config.segments.is_empty() {
+        return;
+    }
+    let region = content.region();
+    let fight = &content.content;
+    let patch_name = match get_patch_name(&config.regions, region, fight.timestamp) {
+        Some(t) => t,
+        None => return,
+    };
+    let key_segments = segments
+        .entry(Fight::new(fight.area_id, fight.boss_id))
+        .or_default()
+        .entry(get_key(region, &patch_name))
+        .or_default();
+    for segment in &config.segments {
+        let classes = key_segments.entry(segment.name()).or_default();
+        for (member, class, dps) in accepted_members(content, &config.classes) {
+            let data = classes
+                .entry(class.clone())
+                .or_insert_with(|| SegmentData::new(backend));
+            if segment.applies(member) {
+                data.with.add(u64::from(dps));
+            } else {
+                data.without.add(u64::from(dps));
+            }
+        }
+    }
+}
+
+/// Add the segments of another run, as if both inputs were processed together
+pub fn merge(segments: &mut SegmentMap, other: SegmentMap) {
+    for (fight, keys) in other {
+        let fight_segments = segments.entry(fight).or_default();
+        for (key, names) in keys {
+            let key_segments = fight_segments.entry(key).or_default();
+            for (name, classes) in names {
+                let name_segments = key_segments.entry(name).or_default();
+                for (class, data) in classes {
+                    match name_segments.entry(class) {
+                        Entry::Occupied(mut t) => t.get_mut().merge(data),
+                        Entry::Vacant(t) => {
+                            t.insert(data);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Dps quantiles of one group of members
+pub struct ExportGroup {
+    /// Members
+    pub count: u64,
+    /// Median dps
+    pub dps_median: u32,
+    /// 90th percentile dps
+    pub dps_percentile_90: u32,
+    /// Configured quantile -> dps
+    pub dps_quantiles: Vec<(f64, u32)>,
+}
+
+impl ExportGroup {
+    /// None for a group without members
+    fn new(mut dps: Distribution, statistics: &Statistics) -> Option<ExportGroup> {
+        let count = dps.len();
+        if count == 0 {
+            return None;
+        }
+        let dps_quantiles = statistics
+            .quantiles
+            .iter()
+            .map(|&quantile| (quantile, dps.quantile(quantile, statistics.method) as u32))
+            .collect();
+        let key_quantiles = dps.key_quantiles(statistics.method);
+        Some(ExportGroup {
+            count,
+            dps_median: key_quantiles.median as u32,
+            dps_percentile_90: key_quantiles.percentile_90 as u32,
+            dps_quantiles,
+        })
+    }
+}
+
+/// Members with and without the buff, a group is None when no member of the class is in it
+pub struct ExportSegment {
+    /// Members with an uptime of at least the threshold
+    pub with: Option<ExportGroup>,
+    /// The other members
+    pub without: Option<ExportGroup>,
+}
+
+/// Statistics of each segment and class of one fight, region and patch
+pub fn export(
+    segments: HashMap<String, HashMap<Class, SegmentData>>,
+    statistics: &Statistics,
+) -> BTreeMap<String, BTreeMap<Class, ExportSegment>> {
+    segments
+        .into_iter()
+        .map(|(name, classes)| {
+            let classes = classes
+                .into_iter()
+                .map(|(class, data)| {
+                    let result = ExportSegment {
+                        with: ExportGroup::new(data.with, statistics),
+                        without: ExportGroup::new(data.without, statistics),
+                    };
+                    (class, result)
+                })
+                .collect();
+            (name, classes)
+        })
+        .collect()
+}
+
+/// One statistic of both groups, None for an empty group
+pub struct SegmentValue {
+    /// `median`, `percentile_90` or `p<N>`
+    pub name: String,
+    /// Value of the members with the buff
+    pub with: Option<u32>,
+    /// Value of the members without the buff
+    pub without: Option<u32>,
+}
+
+impl SegmentValue {
+    /// with - without, when both groups have members
+    pub fn difference(&self) -> Option<i64> {
+        Some(i64::from(self.with?) - i64::from(self.without?))
+    }
+}
+
+impl ExportGroup {
+    // Statistic name -> dps, in a stable order
+    fn values(&self) -> Vec<(String, u32)> {
+        let mut values = vec![
+            ("median".to_string(), self.dps_median),
+            ("percentile_90".to_string(), self.dps_percentile_90),
+        ];
+        for &(quantile, dps) in &self.dps_quantiles {
+            values.push((format!("p{}", quantile_name(quantile)), dps));
+        }
+        values
+    }
+}
+
+impl ExportSegment {
+    /// Median, 90th percentile and configured quantiles of both groups
+    pub fn values(&self) -> Vec<SegmentValue> {
+        let with = self.with.as_ref().map(ExportGroup::values).unwrap_or_default();
+        let without = self.without.as_ref().map(ExportGroup::values).unwrap_or_default();
+        let names = if with.is_empty() { &without } else { &with };
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| SegmentValue {
+                name: name.clone(),
+                with: with.get(i).map(|value| value.1),
+                without: without.get(i).map(|value| value.1),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,273 @@
+//! SQLite export of the accepted encounters and of the statistics.
+
+extern crate rusqlite;
+use self::rusqlite::{params, Connection};
+use config::ClassRegistry;
+use distribution::Summary;
+use process::{EncounterRecord, ExportResult, Fight};
+use std::{fs, path::Path, sync::Mutex};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS encounters (
+    id INTEGER PRIMARY KEY,
+    file TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    patch TEXT NOT NULL,
+    area_id INTEGER NOT NULL,
+    boss_id INTEGER NOT NULL,
+    fight_duration INTEGER NOT NULL,
+    party_size INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS members (
+    id INTEGER PRIMARY KEY,
+    encounter_id INTEGER NOT NULL REFERENCES encounters(id),
+    class TEXT NOT NULL,
+    server TEXT NOT NULL,
+    dps INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS buffs (
+    member_id INTEGER NOT NULL REFERENCES members(id),
+    buff_id TEXT NOT NULL,
+    uptime INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS class_stats (
+    area_id INTEGER NOT NULL,
+    boss_id INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    patch TEXT NOT NULL,
+    class TEXT NOT NULL,
+    role TEXT,
+    count INTEGER NOT NULL,
+    median INTEGER NOT NULL,
+    percentile_90 INTEGER NOT NULL,
+    mean REAL NOT NULL,
+    std_dev REAL NOT NULL,
+    min INTEGER NOT NULL,
+    max INTEGER NOT NULL,
+    iqr INTEGER NOT NULL,
+    trimmed_mean REAL NOT NULL,
+    cv REAL NOT NULL,
+    PRIMARY KEY (area_id, boss_id, region, patch, class)
+);
+CREATE TABLE IF NOT EXISTS class_quantiles (
+    area_id INTEGER NOT NULL,
+    boss_id INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    patch TEXT NOT NULL,
+    class TEXT NOT NULL,
+    quantile REAL NOT NULL,
+    dps INTEGER NOT NULL,
+    PRIMARY KEY (area_id, boss_id, region, patch, class, quantile)
+);
+CREATE TABLE IF NOT EXISTS clear_time_stats (
+    area_id INTEGER NOT NULL,
+    boss_id INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    patch TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    median INTEGER NOT NULL,
+    fastest_10 INTEGER NOT NULL,
+    mean REAL NOT NULL,
+    std_dev REAL NOT NULL,
+    min INTEGER NOT NULL,
+    max INTEGER NOT NULL,
+    iqr INTEGER NOT NULL,
+    trimmed_mean REAL NOT NULL,
+    cv REAL NOT NULL,
+    PRIMARY KEY (area_id, boss_id, region, patch)
+);
+CREATE TABLE IF NOT EXISTS clear_time_quantiles (
+    area_id INTEGER NOT NULL,
+    boss_id INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    patch TEXT NOT NULL,
+    quantile REAL NOT NULL,
+    clear_time INTEGER NOT NULL,
+    PRIMARY KEY (area_id, boss_id, region, patch, quantile)
+);
+CREATE INDEX IF NOT EXISTS encounters_fight ON encounters (area_id, boss_id);
+CREATE INDEX IF NOT EXISTS encounters_patch ON encounters (region, patch);
+CREATE INDEX IF NOT EXISTS members_encounter ON members (encounter_id);
+CREATE INDEX IF NOT EXISTS buffs_member ON buffs (member_id);
+CREATE INDEX IF NOT EXISTS class_stats_patch ON class_stats (region, patch);
+CREATE INDEX IF NOT EXISTS clear_time_stats_patch ON clear_time_stats (region, patch);
+";
+
+/// Encounters of the files parsed so far, and the statistics of the last export.
+/// The connection is shared by the worker threads.
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    /// Keep the encounters already in the file when `append` is set, start from an empty database otherwise
+    pub fn open(filename: &str, append: bool) -> Result<Database, String> {
+        if !append && Path::new(filename).exists() {
+            fs::remove_file(filename).map_err(|e| format!("Unable to replace {}: {}", filename, e))?;
+        }
+        let connection = Connection::open(filename)
+            .and_then(|connection| connection.execute_batch(SCHEMA).map(|_| connection))
+            .map_err(|e| format!("Unable to open {}: {}", filename, e))?;
+        Ok(Database {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Encounters of a whole file are inserted in one transaction, so that an invalid file inserts nothing
+    pub fn insert(&self, file: &str, encounters: &[EncounterRecord]) -> Result<(), String> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+        {
+            let mut insert_encounter = transaction
+                .prepare_cached(
+                    "INSERT INTO encounters (file, timestamp, region, patch, area_id, boss_id, fight_duration, party_size)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                )
+                .map_err(|e| e.to_string())?;
+            let mut insert_member = transaction
+                .prepare_cached("INSERT INTO members (encounter_id, class, server, dps) VALUES (?1, ?2, ?3, ?4)")
+                .map_err(|e| e.to_string())?;
+            let mut insert_buff = transaction
+                .prepare_cached("INSERT INTO buffs (member_id, buff_id, uptime) VALUES (?1, ?2, ?3)")
+                .map_err(|e| e.to_string())?;
+            for encounter in encounters {
+                let encounter_id = insert_encounter
+                    .insert(params![
+                        file,
+                        encounter.timestamp as i64,
+                        encounter.region,
+                        encounter.patch,
+                        encounter.area_id,
+                        encounter.boss_id,
+                        encounter.fight_duration as i64,
+                        encounter.party_size as i64
+                    ])
+                    .map_err(|e| e.to_string())?;
+                for member in &encounter.members {
+                    let member_id = insert_member
+                        .insert(params![encounter_id, member.class, member.server, member.dps])
+                        .map_err(|e| e.to_string())?;
+                    for (buff_id, uptime) in &member.buffs {
+                        insert_buff
+                            .execute(params![member_id, buff_id, uptime])
+                            .map_err(|e| e.to_string())?;
+                    }
+                }
+            }
+        }
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    /// Statistics are replaced on every export, until commit_statistics is called
+    pub fn begin_statistics(&self) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "BEGIN;
+                 DELETE FROM class_stats;
+                 DELETE FROM class_quantiles;
+                 DELETE FROM clear_time_stats;
+                 DELETE FROM clear_time_quantiles;",
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    /// Replace the previous statistics with the ones inserted since begin_statistics
+    pub fn commit_statistics(&self) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch("COMMIT")
+            .map_err(|e| e.to_string())
+    }
+
+    /// Keep the previous statistics, when one of the current ones could not be inserted
+    pub fn rollback_statistics(&self) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch("ROLLBACK")
+            .map_err(|e| e.to_string())
+    }
+
+    /// Must be called before the classes of the result are consumed
+    pub fn insert_statistics(
+        &self,
+        fight: &Fight,
+        region: &str,
+        patch: &str,
+        result: &ExportResult,
+        class_registry: &ClassRegistry,
+    ) -> Result<(), String> {
+        let connection = self.connection.lock().unwrap();
+        let summary = &result.clear_time_summary;
+        connection
+            .execute(
+                "INSERT INTO clear_time_stats
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+                params![
+                    fight.area_id,
+                    fight.boss_id,
+                    region,
+                    patch,
+                    summary.count as i64,
+                    result.clear_time_median as i64,
+                    result.clear_time_fastest_10 as i64,
+                    summary.mean,
+                    summary.std_dev,
+                    summary.min as i64,
+                    summary.max as i64,
+                    summary.iqr as i64,
+                    summary.trimmed_mean,
+                    summary.cv
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        for &(quantile, clear_time) in &result.clear_time_quantiles {
+            connection
+                .execute(
+                    "INSERT INTO clear_time_quantiles VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![fight.area_id, fight.boss_id, region, patch, quantile, clear_time as i64],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        for (class, data) in &result.class {
+            let summary: &Summary = &data.dps_summary;
+            connection
+                .execute(
+                    "INSERT INTO class_stats
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+                    params![
+                        fight.area_id,
+                        fight.boss_id,
+                        region,
+                        patch,
+                        class,
+                        class_registry.role(class).map(|role| role.to_str()),
+                        data.count as i64,
+                        data.dps_median,
+                        data.dps_percentile_90,
+                        summary.mean,
+                        summary.std_dev,
+                        summary.min as i64,
+                        summary.max as i64,
+                        summary.iqr as i64,
+                        summary.trimmed_mean,
+                        summary.cv
+                    ],
+                )
+                .map_err(|e| e.to_string())?;
+            for &(quantile, dps) in &data.dps_quantiles {
+                connection
+                    .execute(
+                        "INSERT INTO class_quantiles VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                        params![fight.area_id, fight.boss_id, region, patch, class, quantile, dps],
+                    )
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Aggregates saved between runs, and the fingerprint of the settings they depend on.
+
+extern crate sha2;
+extern crate xz2;
+use self::sha2::{Digest, Sha256};
+use self::xz2::{read::XzDecoder, write::XzEncoder};
+use config::{ClassRegistry, Config, RegionMap, UsageConfig};
+use process::GlobalData;
+use quality::QualityReport;
+use rules::Rule;
+use segment::Segment;
+use serde_json;
+use std::{
+    fs::File,
+    io,
+    io::{BufReader, BufWriter},
+};
+use trend::TrendBucket;
+
+// Bumped when the layout of the aggregates changes
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Aggregated data, persisted as a snapshot so that later runs or other machines can add data to it
+#[derive(Serialize, Deserialize, Default)]
+pub struct State {
+    /// Aggregates of the accepted fights
+    pub data: GlobalData,
+    /// Accepted and rejected encounters, the invalid files of the run are left out
+    pub quality: QualityReport,
+}
+
+/// Snapshot file: xz compressed json
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    fingerprint: &'a str,
+    state: &'a State,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    version: u32,
+    fingerprint: String,
+    state: State,
+}
+
+impl State {
+    /// No data
+    pub fn new() -> State {
+        State {
+            data: GlobalData::new(),
+            quality: QualityReport::new(),
+        }
+    }
+
+    /// Load a snapshot, only if it was built with the same settings
+    pub fn load(filename: &str, fingerprint: &str) -> Result<State, String> {
+        let file = File::open(filename).map_err(|e| format!("Unable to open {}: {}", filename, e))?;
+        let snapshot: Snapshot =
+            serde_json::from_reader(BufReader::new(XzDecoder::new(BufReader::new(file))))
+                .map_err(|e| format!("Unable to parse {}: {}", filename, e))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Snapshot {} has version {}, expected {}",
+                filename, snapshot.version, SNAPSHOT_VERSION
+            ));
+        }
+        if snapshot.fingerprint != fingerprint {
+            return Err(format!(
+                "Snapshot {} was built with other patch windows, classes, rules, segments, usage, trend, skills or dps steps",
+                filename
+            ));
+        }
+        Ok(snapshot.state)
+    }
+
+    /// Write the snapshot, tagged with the format version and the fingerprint of the settings
+    pub fn save(&self, filename: &str, fingerprint: &str) -> Result<(), String> {
+        let file = File::create(filename).map_err(|e| format!("Unable to create {}: {}", filename, e))?;
+        let snapshot = SnapshotRef {
+            version: SNAPSHOT_VERSION,
+            fingerprint,
+            state: self,
+        };
+        let mut encoder = XzEncoder::new(BufWriter::new(file), 6);
+        serde_json::to_writer(&mut encoder, &snapshot)
+            .map_err(io::Error::from)
+            .and_then(|_| encoder.finish())
+            .map(|_| ())
+            .map_err(|e| format!("Unable to write {}: {}", filename, e))
+    }
+
+    /// Combining two states gives the same result as one run over both inputs
+    pub fn merge(&mut self, other: State) {
+        self.data.merge(other.data);
+        self.quality.merge(other.quality);
+    }
+}
+
+// Settings changing the aggregates, the ones only read when exporting are left out
+#[derive(Serialize)]
+struct AggregateSettings<'a> {
+    regions: &'a RegionMap,
+    classes: &'a ClassRegistry,
+    rules: &'a [Rule],
+    segments: &'a [Segment],
+    usage: &'a UsageConfig,
+    trend: Option<TrendBucket>,
+    skills: bool,
+    dps_steps: u32,
+}
+
+/// Aggregates are only comparable when built with the same patch windows, classes, rules, segments,
+/// usage timezones, trend buckets, skill setting and dps steps.
+/// Other settings (statistics, reports, buff names, CSV columns) only change the exports and can be edited between runs.
+/// The backend is left out: exact values and sketches of any accuracy merge together.
+pub fn fingerprint(config: &Config, dps_steps: u32) -> String {
+    let settings = AggregateSettings {
+        regions: &config.regions,
+        classes: &config.classes,
+        rules: &config.rules,
+        segments: &config.segments,
+        usage: &config.usage,
+        trend: config.trend,
+        skills: config.skills.enabled,
+        dps_steps,
+    };
+    // Through a json value, whose maps are sorted, so that the order of the hash maps does not matter
+    let canonical = serde_json::to_value(&settings)
+        .and_then(|value| serde_json::to_string(&value))
+        .unwrap();
+    format!("{:x}", Sha256::digest(canonical))
+}
@@ -0,0 +1,175 @@
+//! Time series of the class dps and clear times within a patch.
+
+use chrono::DateTime;
+use config::{Config, Timezone};
+use distribution::{Backend, Distribution, QuantileMethod};
+use parse::StatsLog;
+use process::{accepted_members, get_key, get_patch_name, Class, Fight};
+use std::collections::{btree_map, hash_map::Entry, BTreeMap, HashMap};
+
+const DAY: i64 = 24 * 3600;
+
+/// Time buckets of the trend series
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(tag = "bucket", rename_all = "snake_case")]
+pub enum TrendBucket {
+    /// Calendar day, in the timezone of the region
+    Day,
+    /// Calendar week starting on Monday, in the timezone of the region
+    Week,
+    /// Days since the start of the patch, grouped by `days`
+    DaysSincePatch {
+        /// Days per bucket
+        #[serde(default = "default_days")]
+        days: u32,
+    },
+}
+
+fn default_days() -> u32 {
+    1
+}
+
+impl TrendBucket {
+    /// Bucket of an encounter: its first day, as days since the epoch or since the start of the patch
+    pub fn bucket(self, timestamp: u64, patch_start: u64, timezone: Timezone) -> i64 {
+        let offset = timezone.offset(timestamp as i64).local_minus_utc();
+        let local_day = (timestamp as i64 + i64::from(offset)).div_euclid(DAY);
+        match self {
+            TrendBucket::Day => local_day,
+            // 1970-01-01 is a Thursday
+            TrendBucket::Week => local_day - (local_day + 3).rem_euclid(7),
+            TrendBucket::DaysSincePatch { days } => {
+                let day = (timestamp.saturating_sub(patch_start) as i64) / DAY;
+                let days = i64::from(days.max(1));
+                day / days * days
+            }
+        }
+    }
+
+    /// "YYYY-MM-DD" for calendar buckets, the number of days since the start of the patch otherwise
+    pub fn label(self, bucket: i64) -> String {
+        match self {
+            TrendBucket::Day | TrendBucket::Week => DateTime::from_timestamp(bucket * DAY, 0)
+                .map_or_else(|| bucket.to_string(), |date| date.format("%Y-%m-%d").to_string()),
+            TrendBucket::DaysSincePatch { .. } => bucket.to_string(),
+        }
+    }
+}
+
+/// Clear times and class dps of one time bucket
+#[derive(Serialize, Deserialize)]
+pub struct TrendData {
+    /// Clear times, in seconds
+    pub clear_time: Distribution,
+    /// Canonical class -> dps of its members
+    pub classes: HashMap<Class, Distribution>,
+}
+
+impl TrendData {
+    fn new(backend: Backend) -> TrendData {
+        TrendData {
+            clear_time: Distribution::new(backend),
+            classes: HashMap::new(),
+        }
+    }
+
+    fn merge(&mut self, other: TrendData) {
+        self.clear_time.merge(other.clear_time);
+        for (class, dps) in other.classes {
+            match self.classes.entry(class) {
+                Entry::Occupied(mut t) => t.get_mut().merge(dps),
+                Entry::Vacant(t) => {
+                    t.insert(dps);
+                }
+            }
+        }
+    }
+}
+
+/// fight -> region and patch key -> bucket -> data
+pub type TrendMap = HashMap<Fight, HashMap<String, BTreeMap<i64, TrendData>>>;
+
+/// Add an accepted encounter to its bucket, with the same members as process::store
+pub fn store(content: &StatsLog, trends: &mut TrendMap, config: &Config, backend: Backend) {
+    let bucket = match config.trend {
+        Some(t) => t,
+        None => return,
+    };
+    let region = content.region();
+    let fight = &content.content;
+    let patch_name = match get_patch_name(&config.regions, region, fight.timestamp) {
+        Some(t) => t,
+        None => return,
+    };
+    let patch_start = config.regions[region][&patch_name].0;
+    let index = bucket.bucket(fight.timestamp, patch_start, config.usage.timezone(region));
+    let data = trends
+        .entry(Fight::new(fight.area_id, fight.boss_id))
+        .or_default()
+        .entry(get_key(region, &patch_name))
+        .or_default()
+        .entry(index)
+        .or_insert_with(|| TrendData::new(backend));
+    data.clear_time.add(fight.fight_duration);
+    for (_, class, dps) in accepted_members(content, &config.classes) {
+        data.classes
+            .entry(class.clone())
+            .or_insert_with(|| Distribution::new(backend))
+            .add(u64::from(dps));
+    }
+}
+
+/// Add the trends of another run, as if both inputs were processed together
+pub fn merge(trends: &mut TrendMap, other: TrendMap) {
+    for (fight, keys) in other {
+        let fight_trends = trends.entry(fight).or_default();
+        for (key, buckets) in keys {
+            let key_trends = fight_trends.entry(key).or_default();
+            for (index, data) in buckets {
+                match key_trends.entry(index) {
+                    btree_map::Entry::Occupied(mut t) => t.get_mut().merge(data),
+                    btree_map::Entry::Vacant(t) => {
+                        t.insert(data);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Series of one fight, region and patch, ordered by bucket
+pub struct TrendSeries {
+    /// (bucket label, median, time beaten by the fastest 10%)
+    pub clear_time: Vec<(String, u64, u64)>,
+    /// class -> (bucket label, members, median dps, 90th percentile dps)
+    pub classes: HashMap<Class, Vec<(String, u64, u64, u64)>>,
+}
+
+/// Medians and percentiles with the configured method
+pub fn export(
+    buckets: BTreeMap<i64, TrendData>,
+    bucket: TrendBucket,
+    method: QuantileMethod,
+) -> TrendSeries {
+    let mut series = TrendSeries {
+        clear_time: Vec::new(),
+        classes: HashMap::new(),
+    };
+    for (index, mut data) in buckets {
+        let label = bucket.label(index);
+        let clear_time = data.clear_time.key_quantiles(method);
+        series
+            .clear_time
+            .push((label.clone(), clear_time.median, clear_time.percentile_10));
+        for (class, mut dps) in data.classes {
+            let quantiles = dps.key_quantiles(method);
+            series.classes.entry(class).or_default().push((
+                label.clone(),
+                dps.len(),
+                quantiles.median,
+                quantiles.percentile_90,
+            ));
+        }
+    }
+    series
+}
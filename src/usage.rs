@@ -0,0 +1,143 @@
+//! Encounters and distinct parties per day and per hour of the week.
+
+extern crate sha2;
+use self::sha2::{Digest, Sha256};
+use chrono::{DateTime, Datelike, NaiveDate, Timelike};
+use config::UsageConfig;
+use distinct::DistinctCount;
+use parse::StatsLog;
+use process::Fight;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+
+/// Encounters and distinct parties of a time bucket
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct UsageCount {
+    /// Accepted encounters
+    pub encounters: u64,
+    /// Hashes of the parties, see `party_hash`
+    pub parties: DistinctCount,
+}
+
+impl UsageCount {
+    fn add(&mut self, other: &UsageCount) {
+        self.encounters += other.encounters;
+        self.parties.merge(&other.parties);
+    }
+}
+
+/// Usage time series of one region and fight, in the timezone of the region
+#[derive(Serialize, Deserialize, Default)]
+pub struct Usage {
+    /// "YYYY-MM-DD" -> counts
+    pub days: BTreeMap<String, UsageCount>,
+    /// Hour of the week, 0 for Monday 00:00 to 167 for Sunday 23:00 -> counts
+    pub hours_of_week: BTreeMap<u32, UsageCount>,
+}
+
+impl Usage {
+    /// Add the counts of another series, parties seen in both are counted once (up to the error of the estimate)
+    pub fn add(&mut self, other: &Usage) {
+        for (day, count) in &other.days {
+            self.days.entry(day.clone()).or_default().add(count);
+        }
+        for (hour, count) in &other.hours_of_week {
+            self.hours_of_week.entry(*hour).or_default().add(count);
+        }
+    }
+}
+
+/// region -> fight -> usage
+pub type UsageData = HashMap<String, HashMap<Fight, Usage>>;
+
+/// Count an encounter in the day and the hour of the week it happened
+pub fn store(content: &StatsLog, usage: &mut UsageData, config: &UsageConfig) {
+    let region = content.region();
+    let fight = &content.content;
+    let date = match DateTime::from_timestamp(fight.timestamp as i64, 0) {
+        Some(t) => t.with_timezone(&config.timezone(region).offset(fight.timestamp as i64)),
+        None => return,
+    };
+    let party = party_hash(content);
+    let fight_usage = usage
+        .entry(region.to_string())
+        .or_default()
+        .entry(Fight::new(fight.area_id, fight.boss_id))
+        .or_default();
+    let hour_of_week = date.weekday().num_days_from_monday() * 24 + date.hour();
+    let add = |count: &mut UsageCount| {
+        count.encounters += 1;
+        count.parties.insert(party);
+    };
+    add(fight_usage
+        .days
+        .entry(date.format("%Y-%m-%d").to_string())
+        .or_default());
+    add(fight_usage.hours_of_week.entry(hour_of_week).or_default());
+}
+
+/// Add the usage of another run, as if both inputs were processed together
+pub fn merge(usage: &mut UsageData, other: UsageData) {
+    for (region, fights) in other {
+        let region_usage = usage.entry(region).or_default();
+        for (fight, fight_usage) in fights {
+            match region_usage.entry(fight) {
+                Entry::Occupied(mut t) => t.get_mut().add(&fight_usage),
+                Entry::Vacant(t) => {
+                    t.insert(fight_usage);
+                }
+            }
+        }
+    }
+}
+
+/// Stable identifier of a party: its members names and servers, whatever their order
+fn party_hash(content: &StatsLog) -> u64 {
+    let mut members: Vec<String> = content
+        .content
+        .members
+        .iter()
+        .map(|member| format!("{}\t{}", member.player_name, member.player_server))
+        .collect();
+    members.sort_unstable();
+    let digest = Sha256::digest(members.join("\n"));
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// One `<day>:<encounters>;<parties>` line per day, from the first to the last day with an encounter
+pub fn daily_series(usage: &Usage) -> String {
+    let mut result = String::new();
+    let first = usage.days.keys().next().and_then(|day| parse_day(day));
+    let last = usage.days.keys().next_back().and_then(|day| parse_day(day));
+    let (mut day, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return result,
+    };
+    let empty = UsageCount::default();
+    while day <= last {
+        let name = day.format("%Y-%m-%d").to_string();
+        let count = usage.days.get(&name).unwrap_or(&empty);
+        result.push_str(&format!("{}:{};{}\n", name, count.encounters, count.parties.count()));
+        day = match day.succ_opt() {
+            Some(t) => t,
+            None => break,
+        };
+    }
+    result
+}
+
+/// One `<hour of the week>:<encounters>;<parties>` line for each of the 168 hours of the week
+pub fn hour_of_week_series(usage: &Usage) -> String {
+    let empty = UsageCount::default();
+    let mut result = String::new();
+    for hour in 0..168 {
+        let count = usage.hours_of_week.get(&hour).unwrap_or(&empty);
+        result.push_str(&format!("{}:{};{}\n", hour, count.encounters, count.parties.count()));
+    }
+    result
+}
+
+fn parse_day(day: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+}